@@ -0,0 +1,209 @@
+//! Converting messages to and from [bipf](https://github.com/ssbc/bipf), the binary encoding
+//! ssb-db2 stores them in.
+
+use std::convert::TryInto;
+
+use snafu::ResultExt;
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_legacy_msg_data::LegacyF64;
+
+use crate::canonical::{self, CanonicalJson};
+use crate::{Error, InvalidMessage, Result};
+
+const STRING: u64 = 0;
+const BUFFER: u64 = 1;
+const INT: u64 = 2;
+const DOUBLE: u64 = 3;
+const ARRAY: u64 = 4;
+const OBJECT: u64 = 5;
+const BOOLNULL: u64 = 6;
+
+/// The bipf encoding of a `{key, value}` message, such as one returned by
+/// [publish](crate::publish).
+///
+/// Numbers are encoded the way javascript's `bipf` does: integers that fit in 32 bits as ints,
+/// every other number as a double.
+pub fn to_bipf(msg_bytes: &[u8]) -> Result<Vec<u8>> {
+    let message = from_slice::<Value>(msg_bytes).context(InvalidMessage)?;
+    let mut bipf = Vec::new();
+    encode(&message, &mut bipf);
+    Ok(bipf)
+}
+
+/// The `{key, value}` message of its bipf encoding, as [publish](crate::publish) returns it.
+///
+/// This is the inverse of [to_bipf]. Fails with [Error::InvalidBipf] if `bipf` is not a single
+/// bipf value, or holds a buffer, which json has no equivalent for.
+pub fn from_bipf(bipf: &[u8]) -> Result<Vec<u8>> {
+    let (value, rest) = decode(bipf)?;
+    if !rest.is_empty() {
+        return Err(invalid("trailing bytes after the value"));
+    }
+    canonical::DEFAULT.encode_value(&value)
+}
+
+fn encode(value: &Value, out: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    let kind = match value {
+        Value::Null => BOOLNULL,
+        Value::Bool(b) => {
+            body.push(*b as u8);
+            BOOLNULL
+        }
+        Value::Float(n) => {
+            let n = f64::from(*n);
+            if n.fract() == 0.0 && n >= f64::from(i32::MIN) && n <= f64::from(i32::MAX) {
+                body.extend_from_slice(&(n as i32).to_le_bytes());
+                INT
+            } else {
+                body.extend_from_slice(&n.to_le_bytes());
+                DOUBLE
+            }
+        }
+        Value::String(s) => {
+            body.extend_from_slice(s.as_bytes());
+            STRING
+        }
+        Value::Array(values) => {
+            for value in values {
+                encode(value, &mut body);
+            }
+            ARRAY
+        }
+        Value::Object(map) => {
+            for (key, value) in map.iter() {
+                encode(&Value::String(key.clone()), &mut body);
+                encode(value, &mut body);
+            }
+            OBJECT
+        }
+    };
+
+    write_varint((body.len() as u64) << 3 | kind, out);
+    out.extend_from_slice(&body);
+}
+
+// Decode one value from the start of `bytes`, also returning the bytes after it.
+fn decode(bytes: &[u8]) -> Result<(Value, &[u8])> {
+    let (tag, bytes) = read_varint(bytes)?;
+    let len = (tag >> 3) as usize;
+    if bytes.len() < len {
+        return Err(invalid("value is longer than its input"));
+    }
+    let (body, rest) = bytes.split_at(len);
+
+    let value = match tag & 7 {
+        STRING => Value::String(
+            String::from_utf8(body.to_vec()).map_err(|_| invalid("string is not utf-8"))?,
+        ),
+        BUFFER => return Err(invalid("buffers can't be converted to json")),
+        INT => {
+            let bytes = body.try_into().map_err(|_| invalid("int is not 4 bytes"))?;
+            Value::Float(LegacyF64::from_f64(f64::from(i32::from_le_bytes(bytes))).unwrap())
+        }
+        DOUBLE => {
+            let bytes = body
+                .try_into()
+                .map_err(|_| invalid("double is not 8 bytes"))?;
+            let n = LegacyF64::from_f64(f64::from_le_bytes(bytes));
+            Value::Float(n.ok_or_else(|| invalid("double is not finite"))?)
+        }
+        ARRAY => {
+            let mut values = Vec::new();
+            let mut body = body;
+            while !body.is_empty() {
+                let (value, rest) = decode(body)?;
+                values.push(value);
+                body = rest;
+            }
+            Value::Array(values)
+        }
+        OBJECT => {
+            let mut map = RidiculousStringMap::with_capacity(0);
+            let mut body = body;
+            while !body.is_empty() {
+                let (key, rest) = decode(body)?;
+                let key = match key {
+                    Value::String(key) => key,
+                    _ => return Err(invalid("object key is not a string")),
+                };
+                let (value, rest) = decode(rest)?;
+                map.insert(key, value);
+                body = rest;
+            }
+            Value::Object(map)
+        }
+        BOOLNULL => match body {
+            [] => Value::Null,
+            [0] => Value::Bool(false),
+            [1] => Value::Bool(true),
+            _ => return Err(invalid("invalid boolean")),
+        },
+        _ => return Err(invalid("unknown type")),
+    };
+
+    Ok((value, rest))
+}
+
+fn write_varint(mut n: u64, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8])> {
+    let mut n = 0;
+    for (i, byte) in bytes.iter().enumerate().take(9) {
+        n |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((n, &bytes[i + 1..]));
+        }
+    }
+    Err(invalid("invalid varint"))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidBipf {
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{publish, verify_keys_match, Content, Error, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn bipf_roundtrip_keeps_the_key() {
+        use crate::{from_bipf, to_bipf};
+
+        let (pk, sk) = generate_longterm_keypair();
+        let first =
+            publish::<_, &[u8]>(Content::Plain(Post::new("one")), None, &pk, &sk, 1.0).unwrap();
+        let post = Post::new("two 🦀 \"quoted\"").with_channel("rust");
+        let second = publish(
+            Content::Plain(post),
+            Some(&first),
+            &pk,
+            &sk,
+            1470186877575.5,
+        )
+        .unwrap();
+
+        for msg in &[first, second] {
+            let bipf = to_bipf(msg).unwrap();
+            let json = from_bipf(&bipf).unwrap();
+            assert_eq!(&json, msg);
+            assert!(verify_keys_match(&[&json]).is_ok());
+            assert_eq!(
+                ParsedMessage::from_bytes(&json).unwrap().key,
+                ParsedMessage::from_bytes(msg).unwrap().key
+            );
+        }
+
+        assert!(matches!(from_bipf(&[0x0b]), Err(Error::InvalidBipf { .. })));
+    }
+}
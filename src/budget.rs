@@ -0,0 +1,131 @@
+//! Bookkeeping of how many bytes have been published within a sliding time window.
+
+use std::collections::VecDeque;
+
+/// Tracks the bytes published to a feed within a sliding window, so that publishers can throttle
+/// themselves before hitting a pub's size or rate limits.
+///
+/// Times are ssb timestamps, milliseconds since the unix epoch. The [Default] budget is unlimited.
+///
+/// ## Example
+///
+///```
+///  use ssb_publish::FeedBudget;
+///
+///  // Allow 10 KiB per hour.
+///  let mut budget = FeedBudget::new(10 * 1024, 60.0 * 60.0 * 1000.0);
+///  budget.record(&[0; 1024], 0.0);
+///
+///  assert_eq!(budget.used(1.0), 1024);
+///  assert_eq!(budget.remaining(1.0), Some(9 * 1024));
+///  ```
+#[derive(Debug, Clone, Default)]
+pub struct FeedBudget {
+    limit: Option<(usize, f64)>,
+    published: VecDeque<(f64, usize)>,
+}
+
+impl FeedBudget {
+    /// A budget allowing at most `max_bytes` to be published in any window of `window_ms`.
+    pub fn new(max_bytes: usize, window_ms: f64) -> FeedBudget {
+        FeedBudget {
+            limit: Some((max_bytes, window_ms)),
+            published: VecDeque::new(),
+        }
+    }
+
+    /// A budget that never runs out. Published bytes are still tracked.
+    pub fn unlimited() -> FeedBudget {
+        FeedBudget::default()
+    }
+
+    /// Record a published message. `timestamp` is the time it was published at.
+    pub fn record(&mut self, message: &[u8], timestamp: f64) {
+        self.published.push_back((timestamp, message.len()));
+    }
+
+    /// The number of bytes published within the window ending at `now`.
+    ///
+    /// For an unlimited budget this is every byte ever recorded.
+    pub fn used(&self, now: f64) -> usize {
+        self.published
+            .iter()
+            .filter(|(timestamp, _)| self.in_window(*timestamp, now))
+            .map(|(_, len)| len)
+            .sum()
+    }
+
+    /// The number of bytes that may still be published at `now`, or `None` if the budget is
+    /// unlimited.
+    pub fn remaining(&self, now: f64) -> Option<usize> {
+        self.limit
+            .map(|(max_bytes, _)| max_bytes.saturating_sub(self.used(now)))
+    }
+
+    /// Whether a message of `len` bytes may be published at `now` without exceeding the budget.
+    pub fn allows(&self, len: usize, now: f64) -> bool {
+        match self.remaining(now) {
+            Some(remaining) => len <= remaining,
+            None => true,
+        }
+    }
+
+    /// Forget messages that have fallen out of the window ending at `now`.
+    pub fn prune(&mut self, now: f64) {
+        if self.limit.is_none() {
+            return;
+        }
+        while let Some((timestamp, _)) = self.published.front() {
+            if self.in_window(*timestamp, now) {
+                break;
+            }
+            self.published.pop_front();
+        }
+    }
+
+    fn in_window(&self, timestamp: f64, now: f64) -> bool {
+        match self.limit {
+            Some((_, window_ms)) => timestamp > now - window_ms,
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::example_contact;
+    use crate::{publish, Content, FeedBudget};
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn budget_accumulates_published_bytes() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut budget = FeedBudget::new(4096, 1000.0);
+
+        let msg1 = publish::<_, &[u8]>(Content::Plain(example_contact(true)), None, &pk, &sk, 0.0)
+            .unwrap();
+        budget.record(&msg1, 0.0);
+        let msg2 = publish(
+            Content::Plain(example_contact(false)),
+            Some(&msg1),
+            &pk,
+            &sk,
+            500.0,
+        )
+        .unwrap();
+        budget.record(&msg2, 500.0);
+
+        assert_eq!(budget.used(500.0), msg1.len() + msg2.len());
+        assert_eq!(
+            budget.remaining(500.0),
+            Some(4096 - msg1.len() - msg2.len())
+        );
+
+        // The first message falls out of the window.
+        assert_eq!(budget.used(1200.0), msg2.len());
+        budget.prune(1200.0);
+        assert_eq!(budget.used(1200.0), msg2.len());
+
+        assert_eq!(FeedBudget::unlimited().remaining(0.0), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! The JSON encoding that messages are signed and hashed in.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use ssb_legacy_msg::Message;
+use ssb_legacy_msg_data::json::{from_slice, to_vec};
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+
+use crate::{InvalidMessage, LegacyJsonEncodeFailed, Result};
+
+/// An implementation of the canonical (signing) JSON encoding of messages.
+///
+/// Every message key and signature depends on these bytes, so any implementation must produce
+/// exactly the output of [LegacyJson].
+pub(crate) trait CanonicalJson {
+    /// Encode a message value, with or without its signature, in the signing format.
+    fn encode_message<T: Serialize>(&self, message: &Message<T>) -> Result<Vec<u8>>;
+
+    /// Encode an arbitrary value in the signing format.
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>>;
+
+    /// Decode a value, rejecting anything that is not valid legacy JSON.
+    fn decode_value(&self, bytes: &[u8]) -> Result<Value>;
+}
+
+/// The encoding of `ssb_legacy_msg_data`, used unless stated otherwise.
+pub(crate) struct LegacyJson;
+
+/// The encoding used to publish.
+pub(crate) const DEFAULT: LegacyJson = LegacyJson;
+
+impl CanonicalJson for LegacyJson {
+    fn encode_message<T: Serialize>(&self, message: &Message<T>) -> Result<Vec<u8>> {
+        ssb_legacy_msg::json::to_legacy_vec(message, false)
+            .map_err(|_| snafu::NoneError)
+            .context(LegacyJsonEncodeFailed)
+    }
+
+    fn encode_value(&self, value: &Value) -> Result<Vec<u8>> {
+        to_vec(value, false)
+            .map_err(|_| snafu::NoneError)
+            .context(LegacyJsonEncodeFailed)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<Value> {
+        from_slice(bytes).context(InvalidMessage)
+    }
+}
+
+/// `value` with the entries of every object inserted in sorted key order.
+///
+/// Entries with integer-like keys still come first when encoded, as in javascript.
+pub(crate) fn sort_keys(value: &Value) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(values.iter().map(sort_keys).collect()),
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by_key(|&(key, _)| key);
+
+            let mut sorted = RidiculousStringMap::with_capacity(entries.len());
+            for (key, value) in entries {
+                sorted.insert(key.clone(), sort_keys(value));
+            }
+            Value::Object(sorted)
+        }
+        value => value.clone(),
+    }
+}
+
+/// The first top-level key that `content` serializes more than once, if any.
+///
+/// A decoded [Value] keeps only one entry per key, so this looks at the serialized entries
+/// themselves.
+pub(crate) fn duplicate_key<T: Serialize>(content: &T) -> Result<Option<String>> {
+    let bytes = to_vec(content, true)
+        .map_err(|_| snafu::NoneError)
+        .context(LegacyJsonEncodeFailed)?;
+    from_slice::<DuplicateKey>(&bytes)
+        .map(|duplicate| duplicate.0)
+        .context(InvalidMessage)
+}
+
+struct DuplicateKey(Option<String>);
+
+impl<'de> Deserialize<'de> for DuplicateKey {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<DuplicateKey, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DuplicateKeyVisitor)
+    }
+}
+
+struct DuplicateKeyVisitor;
+
+impl<'de> Visitor<'de> for DuplicateKeyVisitor {
+    type Value = DuplicateKey;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any json value")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<DuplicateKey, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut keys = HashSet::new();
+        let mut duplicate = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            map.next_value::<IgnoredAny>()?;
+            if duplicate.is_none() && !keys.insert(key.clone()) {
+                duplicate = Some(key);
+            }
+        }
+
+        Ok(DuplicateKey(duplicate))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<DuplicateKey, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(DuplicateKey(None))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> std::result::Result<DuplicateKey, E> {
+        Ok(DuplicateKey(None))
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> std::result::Result<DuplicateKey, E> {
+        Ok(DuplicateKey(None))
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> std::result::Result<DuplicateKey, E> {
+        Ok(DuplicateKey(None))
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> std::result::Result<DuplicateKey, E> {
+        Ok(DuplicateKey(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssb_legacy_msg::{Content, Message};
+    use ssb_legacy_msg_data::LegacyF64;
+    use ssb_multiformats::multikey::Multikey;
+
+    use crate::canonical::{self, CanonicalJson};
+    use crate::hasher::{self, MessageHasher};
+
+    #[test]
+    fn default_canonical_json_matches_golden_output() {
+        // The signing encoding of a message value and the key it hashes to, as committed when the
+        // encoding was moved behind `CanonicalJson`. Any backend must reproduce both exactly.
+        let unsigned = r##"{
+  "previous": null,
+  "author": "@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519",
+  "sequence": 1,
+  "timestamp": 1470186877575.5,
+  "hash": "sha256",
+  "content": {
+    "1": 0.5,
+    "type": "post",
+    "text": "\"quoted\" \\ back\nslash é 😀 \u0001"
+  }
+}"##;
+        let signed = r##"{
+  "previous": null,
+  "author": "@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519",
+  "sequence": 1,
+  "timestamp": 1470186877575.5,
+  "hash": "sha256",
+  "content": {
+    "1": 0.5,
+    "type": "post",
+    "text": "\"quoted\" \\ back\nslash é 😀 \u0001"
+  },
+  "signature": "QJKWui3oyK6r5dH13xHkEVFhfMZDTXfK2tW21nyfheFClSf69yYK77Itj1BGcOimZ16pj9u3tMArLUCGSscqCQ==.sig.ed25519"
+}"##;
+        let key = "%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256";
+
+        let json = canonical::DEFAULT;
+        let author = b"@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519";
+        let author = Multikey::from_legacy(author).unwrap().0;
+        let content =
+            r#"{"1": 0.5, "type": "post", "text": "\"quoted\" \\ back\nslash é 😀 \u0001"}"#;
+        let mut message = Message {
+            previous: None,
+            author: author.clone(),
+            sequence: 1,
+            timestamp: LegacyF64::from_f64(1470186877575.5).unwrap(),
+            content: Content::Plain(json.decode_value(content.as_bytes()).unwrap()),
+            swapped: false,
+            signature: None,
+        };
+
+        let encoded = json.encode_message(&message).unwrap();
+        assert_eq!(std::str::from_utf8(&encoded).unwrap(), unsigned);
+
+        let signature = b"QJKWui3oyK6r5dH13xHkEVFhfMZDTXfK2tW21nyfheFClSf69yYK77Itj1BGcOimZ16pj9u3tMArLUCGSscqCQ==.sig.ed25519";
+        message.signature = Some(author.sig_from_legacy(signature).unwrap().0);
+
+        let encoded = json.encode_message(&message).unwrap();
+        assert_eq!(std::str::from_utf8(&encoded).unwrap(), signed);
+        assert_eq!(hasher::DEFAULT.hash(&encoded).to_legacy_string(), key);
+    }
+}
@@ -0,0 +1,621 @@
+//! Typed content for common message types.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{ensure, ResultExt};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::json::{from_slice, to_vec};
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_multiformats::multihash::{Multihash, Target};
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{
+    author_of, publish, Content, InvalidFeedId, InvalidMessageId, LegacyJsonEncodeFailed, Result,
+};
+
+/// The content of a `post` message.
+///
+/// `channel` and `recps` are left out of the message when they are empty.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename = "post")]
+pub struct Post {
+    /// The text of the post, usually markdown.
+    pub text: String,
+    /// The channel (hashtag) the post belongs to, without the leading `#`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// The feeds the post is intended for. On a public post this is only a hint.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recps: Vec<Multikey>,
+    /// Feeds, messages and blobs the post refers to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+}
+
+/// A reference from a [Post] to a feed, message or blob.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Mention {
+    /// The id of what is mentioned.
+    pub link: String,
+    /// The name it is mentioned by, eg. a file name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The mime type of a mentioned blob.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    /// The size in bytes of a mentioned blob.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+impl Post {
+    /// A post with just `text`.
+    pub fn new<S: Into<String>>(text: S) -> Post {
+        Post {
+            text: text.into(),
+            channel: None,
+            recps: Vec::new(),
+            mentions: Vec::new(),
+        }
+    }
+
+    /// Set the channel of the post.
+    pub fn with_channel<S: Into<String>>(mut self, channel: S) -> Post {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    /// Set the intended recipients of the post. Each entry must be a feed id.
+    pub fn with_recps(mut self, recps: &[&str]) -> Result<Post> {
+        self.recps = recps
+            .iter()
+            .map(|id| parse_feed_id(id))
+            .collect::<Result<_>>()?;
+        Ok(self)
+    }
+}
+
+/// The content of a `contact` message, following or blocking another feed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename = "contact")]
+pub struct Contact {
+    /// The feed being followed or blocked.
+    pub contact: Multikey,
+    /// Whether `contact` is followed.
+    pub following: bool,
+    /// Whether `contact` is blocked.
+    pub blocking: bool,
+}
+
+/// The content of a `vote` message, eg. liking another message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename = "vote")]
+pub struct Vote {
+    /// What is voted on, and how.
+    pub vote: VoteValue,
+}
+
+/// The `vote` entry of a [Vote].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VoteValue {
+    /// The message voted on.
+    pub link: Multihash,
+    /// `1` to vote for the message, `0` to take a vote back, `-1` to vote against it.
+    pub value: i32,
+    /// How the vote is shown, eg. `"Like"`.
+    pub expression: String,
+}
+
+/// The content of an `about` message, describing a feed.
+///
+/// The fields that are `None` are left out of the message, leaving them as they were.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename = "about")]
+pub struct About {
+    /// The feed being described.
+    pub about: Multikey,
+    /// The display name of the feed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// A description of the feed, usually markdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The blob id of the feed's picture.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+/// The content of a `post:edit` message, replacing the text of an earlier [Post] as in the
+/// `ssb-edit-message` convention.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename = "post:edit")]
+pub struct PostEdit {
+    /// The message being edited, the original post.
+    pub edits: Multihash,
+    /// The new text of the post.
+    pub text: String,
+}
+
+/// The entries of the content object `new` that are not the same in `old`, for publishing only
+/// what changed, eg. in an [About] update.
+///
+/// The `type` entry is always kept so that the result is valid content, but other entries that
+/// identify what is updated, such as the `about` of an [About], must be added back. Entries of
+/// `old` that are missing from `new` can't be represented and are left out. If either value is
+/// not an object, the result is all of `new`.
+pub fn content_diff(old: &Value, new: &Value) -> Value {
+    let (old, new) = match (old, new) {
+        (Value::Object(old), Value::Object(new)) => (old, new),
+        _ => return new.clone(),
+    };
+
+    let mut diff = RidiculousStringMap::with_capacity(new.len());
+    for (key, value) in new.iter() {
+        if key == "type" || old.get(key) != Some(value) {
+            diff.insert(key.clone(), value.clone());
+        }
+    }
+    Value::Object(diff)
+}
+
+// `content` with its `type` entry renamed to `type_field`, in the same position, or `None` if it
+// has no `type` entry to rename or already has a `type_field` entry.
+pub(crate) fn retag(content: &Value, type_field: &str) -> Option<Value> {
+    let content = match content {
+        Value::Object(content) => content,
+        _ => return None,
+    };
+    if content.get("type").is_none() || content.get(type_field).is_some() {
+        return None;
+    }
+
+    let mut retagged = RidiculousStringMap::with_capacity(content.len());
+    for (key, value) in content.iter() {
+        let key = if key == "type" { type_field } else { key };
+        retagged.insert(key.to_owned(), value.clone());
+    }
+    Some(Value::Object(retagged))
+}
+
+// The json value `content` serializes to, as it would be published.
+pub(crate) fn to_value<T: Serialize>(content: &T) -> Result<Value> {
+    let bytes = to_vec(content, true)
+        .map_err(|_| snafu::NoneError)
+        .context(LegacyJsonEncodeFailed)?;
+    from_slice(&bytes)
+        .map_err(|_| snafu::NoneError)
+        .context(LegacyJsonEncodeFailed)
+}
+
+/// How typed content is wrapped into the `content` of a message, for deployments that layer their
+/// own content schema over ssb. See [publish_enveloped].
+pub trait ContentEnvelope {
+    /// The `content` of a message carrying `content`, the json value of the typed content, which
+    /// holds its `type` entry.
+    fn wrap(&self, content: Value) -> Result<Value>;
+}
+
+/// The standard [ContentEnvelope]: the typed content is the `content` of the message, tagged with
+/// its `type` entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TypeTagged;
+
+impl ContentEnvelope for TypeTagged {
+    fn wrap(&self, content: Value) -> Result<Value> {
+        Ok(content)
+    }
+}
+
+/// Publish `content` wrapped by `envelope`, like [publish].
+///
+/// With [TypeTagged] this publishes exactly the same message as `Content::Plain(content)`.
+pub fn publish_enveloped<T, E, P>(
+    content: &T,
+    envelope: &E,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    E: ContentEnvelope + ?Sized,
+    P: AsRef<[u8]>,
+{
+    let content = envelope.wrap(to_value(content)?)?;
+    publish(
+        Content::Plain(content),
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )
+}
+
+/// Publish a [Post], like [publish].
+pub fn publish_post<P: AsRef<[u8]>>(
+    post: Post,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>> {
+    publish(
+        Content::Plain(post),
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )
+}
+
+/// Publish a [PostEdit] changing the text of the post `original` to `new_text`, like [publish].
+///
+/// Fails with [Error::InvalidMessageId](crate::Error::InvalidMessageId) if `original` is a blob
+/// id. Whether `original` is a post of the same feed is up to the caller.
+pub fn publish_edit<P: AsRef<[u8]>>(
+    original: Multihash,
+    new_text: &str,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>> {
+    ensure!(
+        original.0 == Target::Message,
+        InvalidMessageId {
+            id: original.to_legacy_string()
+        }
+    );
+    let edit = PostEdit {
+        edits: original,
+        text: new_text.to_owned(),
+    };
+    publish(
+        Content::Plain(edit),
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )
+}
+
+/// Whether two feed ids refer to the same feed.
+///
+/// Both ids are parsed before comparing them, so surrounding whitespace and a missing leading `@`
+/// (as in ids copied out of some apps) do not matter. Fails if either id is not a feed id.
+pub fn feed_ids_equal(a: &str, b: &str) -> Result<bool> {
+    Ok(parse_loose_feed_id(a)? == parse_loose_feed_id(b)?)
+}
+
+/// A short identifier of the feed of `public_key` for logs: the `@` sigil and the first 8
+/// characters of its id, eg. `@9Zf0se86`.
+///
+/// It is stable, but only meant for telling feeds apart at a glance. Use the full id to identify
+/// a feed.
+pub fn feed_fingerprint(public_key: &PublicKey) -> String {
+    author_of(public_key)
+        .to_legacy_string()
+        .chars()
+        .take(9)
+        .collect()
+}
+
+fn parse_loose_feed_id(id: &str) -> Result<Multikey> {
+    let id = id.trim();
+    if id.starts_with('@') {
+        parse_feed_id(id)
+    } else {
+        parse_feed_id(&format!("@{}", id))
+    }
+}
+
+/// Publish a [Post] of `text` that mentions a blob of `blob_data`, like [publish].
+///
+/// The mention records the blob's `name`, `mime` type and size. Returns the new message and the
+/// blob id, under which the caller must store `blob_data` for others to fetch it.
+#[allow(clippy::too_many_arguments)]
+pub fn publish_post_with_blob<P: AsRef<[u8]>>(
+    text: &str,
+    blob_data: &[u8],
+    name: &str,
+    mime: &str,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<(Vec<u8>, Multihash)> {
+    let blob_id = blob_id(blob_data);
+
+    let mut post = Post::new(text);
+    post.mentions.push(Mention {
+        link: blob_id.to_legacy_string(),
+        name: Some(name.to_owned()),
+        mime: Some(mime.to_owned()),
+        size: Some(blob_data.len() as u64),
+    });
+
+    let message = publish_post(
+        post,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )?;
+    Ok((message, blob_id))
+}
+
+/// The id of a blob: the sha256 hash of its bytes.
+pub fn blob_id(blob_data: &[u8]) -> Multihash {
+    Multihash::from_sha256(Sha256::digest(blob_data).into(), Target::Blob)
+}
+
+pub(crate) fn parse_feed_id(id: &str) -> Result<Multikey> {
+    match Multikey::from_legacy(id.as_bytes()) {
+        Ok((key, [])) => Ok(key),
+        _ => InvalidFeedId { id }.fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{assert_content_roundtrip, EXAMPLE_FEED_ID};
+    use crate::{
+        content_as, content_diff, content_type, extract_content_bytes, feed_fingerprint,
+        feed_ids_equal, publish, publish_edit, publish_enveloped, publish_post,
+        publish_post_with_blob, to_canonical_string, validate_envelope,
+        validate_envelope_with_type_field, About, Contact, Content, ContentEnvelope, Error,
+        Mention, Multihash, ParsedMessage, Post, PostEdit, TypeTagged, Vote, VoteValue,
+    };
+    use sha2::{Digest, Sha256};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::value::Value;
+    use ssb_multiformats::multikey::Multikey;
+    use ssb_validate::validate_message_hash_chain;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn post_with_channel_and_recps() {
+        let (pk, sk) = generate_longterm_keypair();
+        let friend = EXAMPLE_FEED_ID;
+
+        let post = Post::new("hello")
+            .with_channel("rust")
+            .with_recps(&[friend])
+            .unwrap();
+        let msg = publish_post::<&[u8]>(post, None, &pk, &sk, 0.0).unwrap();
+        assert!(validate_message_hash_chain::<_, &[u8]>(&msg, None).is_ok());
+
+        let text = String::from_utf8(msg).unwrap();
+        assert!(text.contains(r#""channel": "rust""#));
+        assert!(text.contains(&format!(
+            r#""recps": [
+        "{}"
+      ]"#,
+            friend
+        )));
+
+        let plain = publish_post::<&[u8]>(Post::new("hello"), None, &pk, &sk, 0.0).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(!plain.contains("channel"));
+        assert!(!plain.contains("recps"));
+
+        match Post::new("hello").with_recps(&["%not-a-feed.sha256"]) {
+            Err(Error::InvalidFeedId { .. }) => (),
+            other => panic!("expected an invalid feed id, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn feed_ids_compare_by_key() {
+        let id = EXAMPLE_FEED_ID;
+        let other = "@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519";
+
+        assert!(feed_ids_equal(id, id).unwrap());
+        assert!(feed_ids_equal(id, &format!(" {}\n", &id[1..])).unwrap());
+        assert!(!feed_ids_equal(id, other).unwrap());
+
+        match feed_ids_equal(id, "@9Zf0se86PotjNqaOt9ue8BNBLkGVLQcLNDw/pRQHY3U=") {
+            Err(Error::InvalidFeedId { .. }) => (),
+            other => panic!("expected an invalid feed id, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn content_types_roundtrip() {
+        let feed = Multikey::from_legacy(EXAMPLE_FEED_ID.as_bytes()).unwrap().0;
+        let msg = Multihash::from_legacy(b"%kmXb3MXtBJaNugcEL/Q7G40DgcAkMNTj3yhmxKHjfCM=.sha256")
+            .unwrap()
+            .0;
+
+        assert_content_roundtrip(Contact {
+            contact: feed.clone(),
+            following: true,
+            blocking: false,
+        });
+        assert_content_roundtrip(Vote {
+            vote: VoteValue {
+                link: msg,
+                value: 1,
+                expression: "Like".to_string(),
+            },
+        });
+        assert_content_roundtrip(About {
+            about: feed.clone(),
+            name: Some("piet".to_string()),
+            description: None,
+            image: None,
+        });
+        assert_content_roundtrip(Post::new("hello").with_channel("rust"));
+    }
+
+    #[test]
+    fn post_mentions_its_blob() {
+        let (pk, sk) = generate_longterm_keypair();
+        let blob = b"not really a png".to_vec();
+
+        let (msg, blob_id) = publish_post_with_blob::<&[u8]>(
+            "look",
+            &blob,
+            "cat.png",
+            "image/png",
+            None,
+            &pk,
+            &sk,
+            0.0,
+        )
+        .unwrap();
+
+        let expected = Sha256::digest(&blob);
+        assert_eq!(
+            blob_id.to_legacy_string(),
+            format!("&{}.sha256", base64::encode(&expected))
+        );
+
+        let post = content_as::<Post>(&msg).unwrap();
+        assert_eq!(
+            post.mentions,
+            vec![Mention {
+                link: blob_id.to_legacy_string(),
+                name: Some("cat.png".to_string()),
+                mime: Some("image/png".to_string()),
+                size: Some(blob.len() as u64),
+            }]
+        );
+        assert!(verify_message(&msg).is_ok());
+    }
+
+    #[test]
+    fn fingerprint_is_a_stable_prefix_of_the_feed_id() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (other_pk, _) = generate_longterm_keypair();
+
+        let fingerprint = feed_fingerprint(&pk);
+        assert_eq!(fingerprint.len(), 9);
+        assert_eq!(fingerprint, feed_fingerprint(&pk));
+        assert_ne!(fingerprint, feed_fingerprint(&other_pk));
+
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        let author = ParsedMessage::from_bytes(&msg).unwrap().author;
+        assert!(author.to_legacy_string().starts_with(&fingerprint));
+    }
+
+    #[test]
+    fn about_diff_has_only_changed_fields() {
+        use crate::content::to_value;
+
+        let (pk, _) = generate_longterm_keypair();
+        let about = |description: &str, image: Option<&str>| About {
+            about: crate::author_of(&pk),
+            name: Some("alice".to_owned()),
+            description: Some(description.to_owned()),
+            image: image.map(ToOwned::to_owned),
+        };
+        let old = to_value(&about("old", None)).unwrap();
+        let new = to_value(&about("new", Some("&blob"))).unwrap();
+
+        let diff = content_diff(&old, &new);
+        let entries = match &diff {
+            Value::Object(diff) => diff,
+            _ => panic!("diff is not an object"),
+        };
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["type", "description", "image"]);
+
+        let mut applied = old.clone();
+        if let Value::Object(applied) = &mut applied {
+            for (key, value) in entries.iter() {
+                applied.insert(key.clone(), value.clone());
+            }
+        }
+        assert_eq!(applied, new);
+    }
+
+    #[test]
+    fn edits_reference_the_original_post() {
+        use ssb_multiformats::multihash::Target;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let original = publish_post(Post::new("helo"), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let original_key = ParsedMessage::from_bytes(&original).unwrap().key;
+
+        let edit = publish_edit(
+            original_key.clone(),
+            "hello",
+            Some(&original),
+            &pk,
+            &sk,
+            1.0,
+        )
+        .unwrap();
+        verify_message(&edit).unwrap();
+        validate_envelope(&edit).unwrap();
+        assert_eq!(content_type(&edit).unwrap().as_deref(), Some("post:edit"));
+        assert_eq!(
+            content_as::<PostEdit>(&edit).unwrap(),
+            PostEdit {
+                edits: original_key,
+                text: "hello".to_owned(),
+            }
+        );
+
+        let blob = Multihash::from_sha256([0; 32], Target::Blob);
+        match publish_edit(blob.clone(), "hello", Some(&original), &pk, &sk, 1.0) {
+            Err(Error::InvalidMessageId { id }) => assert_eq!(id, blob.to_legacy_string()),
+            other => panic!("expected InvalidMessageId, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn content_envelopes_wrap_typed_content() {
+        use ssb_legacy_msg_data::value::RidiculousStringMap;
+
+        // Moves the `type` out of the content, as `{data: {..}, kind: type}`.
+        struct DataKind;
+
+        impl ContentEnvelope for DataKind {
+            fn wrap(&self, content: Value) -> Result<Value, Error> {
+                let content = match content {
+                    Value::Object(content) => content,
+                    _ => return Err(Error::ContentNotObject {}),
+                };
+                let mut data = RidiculousStringMap::with_capacity(content.len());
+                let mut kind = Value::Null;
+                for (key, value) in content.iter() {
+                    match key.as_str() {
+                        "type" => kind = value.clone(),
+                        _ => {
+                            data.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+
+                let mut wrapped = RidiculousStringMap::with_capacity(2);
+                wrapped.insert("data".to_owned(), Value::Object(data));
+                wrapped.insert("kind".to_owned(), kind);
+                Ok(Value::Object(wrapped))
+            }
+        }
+
+        let (pk, sk) = generate_longterm_keypair();
+        let post = Post::new("wrapped");
+
+        let standard = publish_enveloped(&post, &TypeTagged, None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let plain = publish(Content::Plain(post.clone()), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        assert_eq!(standard, plain);
+
+        let msg = publish_enveloped(&post, &DataKind, None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        verify_message(&msg).unwrap();
+        assert!(validate_envelope_with_type_field(&msg, "kind").is_ok());
+        assert_eq!(
+            to_canonical_string(&extract_content_bytes(&msg).unwrap()).unwrap(),
+            r#"{"data":{"text":"wrapped"},"kind":"post"}"#
+        );
+    }
+}
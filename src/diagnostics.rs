@@ -0,0 +1,110 @@
+//! Non-fatal warnings about risky publishing options. Enable the `diagnostics` feature to use them.
+
+use std::fmt;
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+
+use crate::{publish_with_options, Content, PublishOptions, Result};
+
+/// Something risky about how a message was published, that didn't stop it from being published.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// Published with [PublishOptions::check_previous_author] turned off.
+    PreviousAuthorCheckDisabled,
+    /// Published without a [PublishOptions::max_future_skew], so the timestamp wasn't checked.
+    FutureSkewCheckDisabled,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::PreviousAuthorCheckDisabled => {
+                write!(f, "published with the previous author check disabled")
+            }
+            Warning::FutureSkewCheckDisabled => {
+                write!(
+                    f,
+                    "published without checking the timestamp against the clock"
+                )
+            }
+        }
+    }
+}
+
+/// The warnings publishing with `options` produces, in a fixed order.
+pub fn warnings_for(options: &PublishOptions) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+
+    if !options.check_previous_author {
+        warnings.push(Warning::PreviousAuthorCheckDisabled);
+    }
+    if options.max_future_skew.is_none() {
+        warnings.push(Warning::FutureSkewCheckDisabled);
+    }
+
+    warnings
+}
+
+/// Publish a new message, like [publish_with_options], also returning the [Warning]s about the
+/// `options` it was published with.
+pub fn publish_with_diagnostics<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+    options: &PublishOptions,
+) -> Result<(Vec<u8>, Vec<Warning>)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let message = publish_with_options(
+        content,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+        options,
+    )?;
+
+    Ok((message, warnings_for(options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Content, Post, PublishOptions};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn risky_options_produce_warnings() {
+        use crate::{publish_with_diagnostics, Warning};
+
+        let (pk, sk) = generate_longterm_keypair();
+
+        let post = Content::Plain(Post::new("hi"));
+        let (_, warnings) =
+            publish_with_diagnostics::<_, &[u8]>(post, None, &pk, &sk, 0.0, &Default::default())
+                .unwrap();
+        assert!(warnings.is_empty());
+
+        let options = PublishOptions {
+            check_previous_author: false,
+            max_future_skew: None,
+            ..PublishOptions::default()
+        };
+        let post = Content::Plain(Post::new("hi"));
+        let (msg, warnings) =
+            publish_with_diagnostics::<_, &[u8]>(post, None, &pk, &sk, 0.0, &options).unwrap();
+        assert!(verify_message(&msg).is_ok());
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::PreviousAuthorCheckDisabled,
+                Warning::FutureSkewCheckDisabled
+            ]
+        );
+    }
+}
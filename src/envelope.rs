@@ -0,0 +1,265 @@
+//! A hand-written structural check of the legacy `{key, value}` message envelope.
+
+use snafu::ensure;
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_multiformats::multihash::{Multihash, Target};
+use ssb_multiformats::multikey::{Multikey, Multisig};
+
+use crate::hasher::{self, MessageHasher};
+use crate::{InvalidEnvelope, Result, SignatureSuiteMismatch, DEFAULT_TYPE_FIELD};
+
+// The entries of a message value, in the order they must appear. `author` and `sequence` may be
+// swapped.
+const VALUE_FIELDS: [&str; 7] = [
+    "previous",
+    "author",
+    "sequence",
+    "timestamp",
+    "hash",
+    "content",
+    "signature",
+];
+
+/// Check that `msg_bytes` is a structurally valid `{key, value}` ssb message.
+///
+/// This checks the field names, their order and types, and the value constraints on each field
+/// (sigils, suffixes, sequence numbering). It does _not_ verify the signature or the hash. Next to
+/// `key` and `value`, the message may have the `recps` hint of
+/// [publish_private](crate::publish_private).
+pub fn validate_envelope(msg_bytes: &[u8]) -> Result<()> {
+    validate_envelope_with_type_field(msg_bytes, DEFAULT_TYPE_FIELD)
+}
+
+/// [validate_envelope] for content schemas that tag content objects with the entry `type_field`
+/// instead of `type`.
+pub fn validate_envelope_with_type_field(msg_bytes: &[u8], type_field: &str) -> Result<()> {
+    let msg = from_slice::<Value>(msg_bytes).map_err(|_| invalid("message is not valid json"))?;
+    let msg = as_object(&msg, "message")?;
+
+    let keys: Vec<&str> = msg.iter().map(|(key, _)| key.as_str()).collect();
+    ensure!(
+        keys == ["key", "value"] || keys == ["key", "value", "recps"],
+        InvalidEnvelope {
+            reason: "message must have exactly the entries `key` and `value`, and maybe `recps`"
+        }
+    );
+    if let Some(recps) = msg.get("recps") {
+        validate_recps(recps)?;
+    }
+
+    let key = as_str(msg.get("key"), "key")?;
+    ensure!(
+        parse_multihash(key) == Some(Target::Message),
+        InvalidEnvelope {
+            reason: "`key` is not a message id"
+        }
+    );
+
+    let value = as_object(msg.get("value").unwrap(), "value")?;
+    validate_value(value, type_field)
+}
+
+fn validate_value(value: &RidiculousStringMap<Value>, type_field: &str) -> Result<()> {
+    let mut keys: Vec<&str> = value.iter().map(|(key, _)| key.as_str()).collect();
+    if keys.get(1) == Some(&"sequence") && keys.get(2) == Some(&"author") {
+        keys.swap(1, 2);
+    }
+    ensure!(
+        keys == VALUE_FIELDS,
+        InvalidEnvelope {
+            reason: "`value` entries are missing, unknown or out of order"
+        }
+    );
+
+    let sequence = match value.get("sequence") {
+        Some(Value::Float(seq)) => f64::from(*seq),
+        _ => return Err(invalid("`sequence` must be a number")),
+    };
+    ensure!(
+        sequence >= 1.0 && sequence.fract() == 0.0,
+        InvalidEnvelope {
+            reason: "`sequence` must be a positive integer"
+        }
+    );
+
+    match value.get("previous") {
+        Some(Value::Null) => ensure!(
+            sequence == 1.0,
+            InvalidEnvelope {
+                reason: "`previous` may only be null for the first message"
+            }
+        ),
+        Some(Value::String(previous)) => ensure!(
+            sequence > 1.0 && parse_multihash(previous) == Some(Target::Message),
+            InvalidEnvelope {
+                reason: "`previous` must be a message id, and the first message has none"
+            }
+        ),
+        _ => return Err(invalid("`previous` must be null or a string")),
+    }
+
+    let author = match Multikey::from_legacy(as_str(value.get("author"), "author")?.as_bytes()) {
+        Ok((author, [])) => author,
+        _ => return Err(invalid("`author` is not a feed id")),
+    };
+
+    match value.get("timestamp") {
+        Some(Value::Float(_)) => (),
+        _ => return Err(invalid("`timestamp` must be a number")),
+    }
+
+    let algorithm = hasher::DEFAULT.algorithm();
+    if as_str(value.get("hash"), "hash")? != algorithm {
+        return Err(invalid(format!("`hash` must be \"{}\"", algorithm)));
+    }
+
+    match value.get("content") {
+        Some(Value::Object(content)) => match content.get(type_field) {
+            Some(Value::String(_)) => (),
+            _ => {
+                return Err(invalid(format!(
+                    "`content` objects must have a string `{}`",
+                    type_field
+                )))
+            }
+        },
+        Some(Value::String(boxed)) => ensure!(
+            boxed.ends_with(".box"),
+            InvalidEnvelope {
+                reason: "encrypted `content` must end in `.box`"
+            }
+        ),
+        _ => {
+            return Err(invalid(
+                "`content` must be an object or an encrypted string",
+            ))
+        }
+    }
+
+    parse_signature(&author, as_str(value.get("signature"), "signature")?)?;
+
+    Ok(())
+}
+
+// Check the `recps` hint of a private message, see `publish_private`.
+fn validate_recps(recps: &Value) -> Result<()> {
+    let recps = match recps {
+        Value::Array(recps) => recps,
+        _ => return Err(invalid("`recps` must be an array")),
+    };
+    for recipient in recps {
+        match Multikey::from_legacy(as_str(Some(recipient), "recps")?.as_bytes()) {
+            Ok((_, [])) => (),
+            _ => return Err(invalid("`recps` must only hold feed ids")),
+        }
+    }
+    Ok(())
+}
+
+/// Parse the legacy `signature` of a message by `author`.
+///
+/// Fails with [Error::SignatureSuiteMismatch](crate::Error::SignatureSuiteMismatch) if the
+/// signature's suffix names a different algorithm than the author's key, eg. a `.sig.ed25519`
+/// signature must belong to an `.ed25519` key.
+pub fn parse_signature(author: &Multikey, signature: &str) -> Result<Multisig> {
+    let key_suite = suite(&author.to_legacy_string()).to_owned();
+    let signature_suite = match signature.find(".sig.") {
+        Some(i) => &signature[i + ".sig.".len()..],
+        None => return Err(invalid("`signature` must end in `.sig.<suite>`")),
+    };
+    ensure!(
+        signature_suite == key_suite,
+        SignatureSuiteMismatch {
+            key_suite,
+            signature_suite
+        }
+    );
+
+    match author.sig_from_legacy(signature.as_bytes()) {
+        Ok((signature, [])) => Ok(signature),
+        _ => Err(invalid("`signature` is not a valid signature")),
+    }
+}
+
+// The algorithm suffix of a legacy key, eg. "ed25519".
+fn suite(legacy: &str) -> &str {
+    legacy.rsplit('.').next().unwrap_or_default()
+}
+
+fn parse_multihash(s: &str) -> Option<Target> {
+    match Multihash::from_legacy(s.as_bytes()) {
+        Ok((hash, [])) => Some(hash.0),
+        _ => None,
+    }
+}
+
+fn as_object<'a>(value: &'a Value, name: &str) -> Result<&'a RidiculousStringMap<Value>> {
+    match value {
+        Value::Object(map) => Ok(map),
+        _ => Err(invalid(format!("`{}` must be an object", name))),
+    }
+}
+
+fn as_str<'a>(value: Option<&'a Value>, name: &str) -> Result<&'a str> {
+    match value {
+        Some(Value::String(s)) => Ok(s),
+        _ => Err(invalid(format!("`{}` must be a string", name))),
+    }
+}
+
+fn invalid<S: Into<String>>(reason: S) -> crate::Error {
+    crate::Error::InvalidEnvelope {
+        reason: reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_signature, publish, validate_envelope, Content, Error, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::json::from_slice;
+    use ssb_legacy_msg_data::value::Value;
+
+    #[test]
+    fn signature_suffix_must_match_the_key() {
+        let (pk, sk) = generate_longterm_keypair();
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        let author = ParsedMessage::from_bytes(&msg).unwrap().author;
+
+        let value = match from_slice::<Value>(&msg).unwrap() {
+            Value::Object(msg) => msg.get("value").unwrap().clone(),
+            _ => panic!("message is not an object"),
+        };
+        let signature = match value {
+            Value::Object(value) => match value.get("signature") {
+                Some(Value::String(signature)) => signature.clone(),
+                _ => panic!("signature is not a string"),
+            },
+            _ => panic!("value is not an object"),
+        };
+
+        assert!(parse_signature(&author, &signature).is_ok());
+
+        let mismatched = signature.replace(".sig.ed25519", ".sig.secp256k1");
+        match parse_signature(&author, &mismatched) {
+            Err(Error::SignatureSuiteMismatch {
+                key_suite,
+                signature_suite,
+            }) => {
+                assert_eq!(key_suite, "ed25519");
+                assert_eq!(signature_suite, "secp256k1");
+            }
+            other => panic!("expected a suite mismatch, got {:?}", other),
+        }
+
+        let tampered = String::from_utf8(msg)
+            .unwrap()
+            .replace(&signature, &mismatched);
+        match validate_envelope(tampered.as_bytes()) {
+            Err(Error::SignatureSuiteMismatch { .. }) => (),
+            other => panic!("expected a suite mismatch, got {:?}", other),
+        }
+    }
+}
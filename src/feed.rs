@@ -0,0 +1,458 @@
+//! Publishing a sequence of messages to one feed.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multihash::Multihash;
+
+use crate::validate;
+use crate::{
+    author_of, legacy_timestamp, publish_parsed, Content, EmptyFeedTail, NotFeedTip, ParsedMessage,
+    PreviousMessageAuthorIsIncorrect, PublishOptions, PublishingTooFast, Result, TimestampPolicy,
+    UnexpectedSequence,
+};
+
+/// A feed being published to, that keeps track of its latest message.
+///
+/// ```
+/// use ssb_publish::{Content, Feed, Post};
+/// use ssb_crypto::generate_longterm_keypair;
+///
+/// let (pk, sk) = generate_longterm_keypair();
+/// let mut feed = Feed::new(pk, sk);
+///
+/// feed.publish(Content::Plain(Post::new("one")), 0.0).unwrap();
+/// feed.publish(Content::Plain(Post::new("two")), 1.0).unwrap();
+///
+/// assert_eq!(feed.latest().unwrap().sequence, 2);
+/// ```
+#[derive(Clone)]
+pub struct Feed {
+    public_key: PublicKey,
+    secret_key: SecretKey,
+    latest: Option<ParsedMessage>,
+    options: PublishOptions,
+    min_interval: Option<Duration>,
+    clock: Arc<dyn Fn() -> Duration + Send + Sync>,
+    last_published_at: Option<Duration>,
+    trusted_from: Option<u64>,
+}
+
+/// The position of a [Feed], for persisting it across restarts. See [Feed::save_state].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeedState {
+    /// The key of the latest message, or `None` if nothing has been published.
+    pub previous: Option<Multihash>,
+    /// The sequence number of the latest message, `0` if nothing has been published.
+    pub sequence: u64,
+    /// The timestamp of the latest message, `0` if nothing has been published.
+    pub timestamp: f64,
+}
+
+impl fmt::Debug for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Feed")
+            .field("public_key", &self.public_key)
+            .field("latest", &self.latest)
+            .field("options", &self.options)
+            .field("min_interval", &self.min_interval)
+            .field("last_published_at", &self.last_published_at)
+            .field("trusted_from", &self.trusted_from)
+            .finish()
+    }
+}
+
+impl Feed {
+    /// A feed with no messages yet, published to with the default [PublishOptions].
+    pub fn new(public_key: PublicKey, secret_key: SecretKey) -> Feed {
+        Feed {
+            public_key,
+            secret_key,
+            latest: None,
+            options: PublishOptions::default(),
+            min_interval: None,
+            clock: Arc::new(system_clock),
+            last_published_at: None,
+            trusted_from: None,
+        }
+    }
+
+    /// Use `options` for every message published after this.
+    pub fn with_options(mut self, options: PublishOptions) -> Feed {
+        self.options = options;
+        self
+    }
+
+    /// Refuse to publish again until `min_interval` has passed since the last message published
+    /// with this `Feed`, failing with [Error::PublishingTooFast](crate::Error::PublishingTooFast).
+    ///
+    /// There is no limit by default.
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Feed {
+        self.min_interval = Some(min_interval);
+        self
+    }
+
+    /// Read the time from `clock`, as a duration since the unix epoch, instead of the system clock.
+    ///
+    /// The time is only used to enforce [Feed::with_min_interval], not for timestamps.
+    pub fn with_clock<C>(mut self, clock: C) -> Feed
+    where
+        C: Fn() -> Duration + Send + Sync + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Continue the feed after `published_bytes`, a `{key, value}` message exactly as returned by
+    /// [publish](crate::publish) or [Feed::publish].
+    ///
+    /// The message becomes the feed's latest message. It must have been published by this feed.
+    pub fn continue_from(&mut self, published_bytes: &[u8]) -> Result<()> {
+        let message = ParsedMessage::from_bytes(published_bytes)?;
+        ensure!(
+            message.author == author_of(&self.public_key),
+            PreviousMessageAuthorIsIncorrect
+        );
+
+        self.latest = Some(message);
+        Ok(())
+    }
+
+    /// The feed's position, from which [Feed::restore_state] continues it.
+    ///
+    /// Only the position is saved. Options, the clock and the minimum interval are set again when
+    /// restoring, and the keys must be stored separately.
+    pub fn save_state(&self) -> FeedState {
+        match &self.latest {
+            Some(latest) => FeedState {
+                previous: Some(latest.key.clone()),
+                sequence: latest.sequence,
+                timestamp: f64::from(latest.timestamp),
+            },
+            None => FeedState {
+                previous: None,
+                sequence: 0,
+                timestamp: 0.0,
+            },
+        }
+    }
+
+    /// A feed continuing from a `state` saved with [Feed::save_state], published to with the
+    /// default [PublishOptions].
+    ///
+    /// `state` is trusted to belong to the feed of `public_key`, nothing is read back to check it.
+    pub fn restore_state(
+        state: FeedState,
+        public_key: PublicKey,
+        secret_key: SecretKey,
+    ) -> Result<Feed> {
+        // Built by hand, the state has no author and its timestamp is not yet a legacy float.
+        let latest = match state.previous {
+            Some(key) => Some(ParsedMessage {
+                key,
+                author: author_of(&public_key),
+                sequence: state.sequence,
+                timestamp: legacy_timestamp(state.timestamp)?,
+            }),
+            None => None,
+        };
+
+        Ok(Feed {
+            latest,
+            ..Feed::new(public_key, secret_key)
+        })
+    }
+
+    /// A feed continuing from `messages`, the `{key, value}` messages at the end of a feed of
+    /// which only the messages from sequence number `known_start_seq` on are available, eg. after
+    /// a partial replication.
+    ///
+    /// The tail is validated from its first message on: each message must follow the one before
+    /// it, be signed by this feed, and the first must have sequence number `known_start_seq`.
+    /// Everything before that is trusted without being checked, and the feed reports where that
+    /// trust begins in [Feed::trusted_from].
+    pub fn from_partial_tail<I>(
+        messages: I,
+        known_start_seq: u64,
+        public_key: PublicKey,
+        secret_key: SecretKey,
+    ) -> Result<Feed>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut previous: Option<I::Item> = None;
+
+        for message in messages {
+            match &previous {
+                Some(previous) => {
+                    validate::check_link(message.as_ref(), Some(previous.as_ref()))?;
+                }
+                None => {
+                    let first = ParsedMessage::from_bytes(message.as_ref())?;
+                    ensure!(
+                        first.sequence == known_start_seq,
+                        UnexpectedSequence {
+                            expected: known_start_seq,
+                            actual: first.sequence
+                        }
+                    );
+                }
+            }
+            validate::check_signature(message.as_ref())?;
+            previous = Some(message);
+        }
+
+        let tail = previous.context(EmptyFeedTail)?;
+        let mut feed = Feed::new(public_key, secret_key);
+        feed.continue_from(tail.as_ref())?;
+        feed.trusted_from = Some(known_start_seq);
+        Ok(feed)
+    }
+
+    /// The sequence number from which the feed's history was validated, if it was created with
+    /// [Feed::from_partial_tail]. The messages before it were never seen.
+    ///
+    /// `None` if the feed's history was not taken on trust.
+    pub fn trusted_from(&self) -> Option<u64> {
+        self.trusted_from
+    }
+
+    /// The public key of the feed.
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// The latest message of the feed, or `None` if nothing has been published.
+    pub fn latest(&self) -> Option<&ParsedMessage> {
+        self.latest.as_ref()
+    }
+
+    /// Publish a new message after the feed's latest message, and make it the latest.
+    pub fn publish<T: Serialize>(
+        &mut self,
+        content: Content<T>,
+        timestamp: f64,
+    ) -> Result<Vec<u8>> {
+        let now = (self.clock)();
+        if let (Some(min_interval), Some(last_published_at)) =
+            (self.min_interval, self.last_published_at)
+        {
+            let elapsed = now.checked_sub(last_published_at).unwrap_or_default();
+            ensure!(
+                elapsed >= min_interval,
+                PublishingTooFast {
+                    retry_in: min_interval - elapsed
+                }
+            );
+        }
+
+        let (message, entry) = publish_parsed(
+            content,
+            self.latest.as_ref(),
+            &self.public_key,
+            &self.secret_key,
+            legacy_timestamp(timestamp)?,
+            &self.options,
+        )?;
+
+        self.latest = Some(ParsedMessage::from(entry));
+        self.last_published_at = Some(now);
+        Ok(message)
+    }
+
+    /// Publish a new message after `previous_bytes`, a `{key, value}` message, like
+    /// [Feed::publish].
+    ///
+    /// This is for callers that keep passing the previous message themselves. If the feed already
+    /// has a latest message, `previous_bytes` must be it: publishing after an older message would
+    /// fork the feed, so it fails with [Error::NotFeedTip](crate::Error::NotFeedTip). Otherwise the
+    /// feed continues from `previous_bytes`, as with [Feed::continue_from].
+    pub fn publish_onto<T: Serialize>(
+        &mut self,
+        content: Content<T>,
+        previous_bytes: &[u8],
+        timestamp: f64,
+    ) -> Result<Vec<u8>> {
+        match &self.latest {
+            Some(tip) => {
+                let previous = ParsedMessage::from_bytes(previous_bytes)?;
+                ensure!(
+                    previous.key == tip.key,
+                    NotFeedTip {
+                        previous: previous.key,
+                        tip: tip.key.clone()
+                    }
+                );
+            }
+            None => self.continue_from(previous_bytes)?,
+        }
+
+        self.publish(content, timestamp)
+    }
+
+    /// Publish a new message, like [Feed::publish], with a timestamp chosen by `policy`.
+    pub fn publish_with_policy<T: Serialize>(
+        &mut self,
+        content: Content<T>,
+        policy: &mut TimestampPolicy,
+    ) -> Result<Vec<u8>> {
+        self.publish(content, policy.next_timestamp())
+    }
+}
+
+fn system_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{validate_feed, Content, Error, Feed, FeedState, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_validate::validate_message_hash_chain;
+
+    #[test]
+    fn feed_continues_from_its_own_output() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut previous: Option<Vec<u8>> = None;
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for i in 0..3 {
+            // A new `Feed` each time, so it only knows what it reads back.
+            let mut publisher = Feed::new(pk, sk.clone());
+            if let Some(previous) = &previous {
+                publisher.continue_from(previous).unwrap();
+            }
+            let msg = publisher
+                .publish(Content::Plain(Post::new(format!("{}", i))), i as f64)
+                .unwrap();
+            assert_eq!(publisher.latest().unwrap().sequence, i + 1);
+
+            previous = Some(msg.clone());
+            feed.push(msg);
+        }
+
+        assert_eq!(validate_feed(&feed).unwrap(), feed.last());
+
+        let (other_pk, other_sk) = generate_longterm_keypair();
+        match Feed::new(other_pk, other_sk).continue_from(&feed[2]) {
+            Err(Error::PreviousMessageAuthorIsIncorrect {}) => (),
+            other => panic!("expected the author to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn feed_enforces_a_minimum_interval() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let now = Arc::new(AtomicU64::new(1_000));
+        let clock = now.clone();
+        let mut feed = Feed::new(pk, sk)
+            .with_min_interval(Duration::from_millis(500))
+            .with_clock(move || Duration::from_millis(clock.load(Ordering::SeqCst)));
+
+        feed.publish(Content::Plain(Post::new("one")), 0.0).unwrap();
+
+        now.store(1_200, Ordering::SeqCst);
+        match feed.publish(Content::Plain(Post::new("two")), 1.0) {
+            Err(Error::PublishingTooFast { retry_in }) => {
+                assert_eq!(retry_in, Duration::from_millis(300))
+            }
+            other => panic!("expected publishing to be too fast, got {:?}", other),
+        }
+
+        now.store(1_500, Ordering::SeqCst);
+        feed.publish(Content::Plain(Post::new("two")), 1.0).unwrap();
+        assert_eq!(feed.latest().unwrap().sequence, 2);
+    }
+
+    #[test]
+    fn feed_state_restores_position() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed = Feed::new(pk, sk.clone());
+        let empty = serde_json::to_string(&feed.save_state()).unwrap();
+        feed.publish(Content::Plain(Post::new("one")), 1.0).unwrap();
+        let last = feed.publish(Content::Plain(Post::new("two")), 2.0).unwrap();
+        let saved = serde_json::to_string(&feed.save_state()).unwrap();
+        drop(feed);
+
+        let state: FeedState = serde_json::from_str(&saved).unwrap();
+        let mut feed = Feed::restore_state(state, pk, sk.clone()).unwrap();
+        let next = feed
+            .publish(Content::Plain(Post::new("three")), 3.0)
+            .unwrap();
+        assert!(validate_message_hash_chain(&next, Some(&last)).is_ok());
+        assert_eq!(feed.latest().unwrap().sequence, 3);
+
+        let state: FeedState = serde_json::from_str(&empty).unwrap();
+        let feed = Feed::restore_state(state, pk, sk).unwrap();
+        assert!(feed.latest().is_none());
+    }
+
+    #[test]
+    fn feed_rejects_publishing_onto_an_old_message() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed = Feed::new(pk, sk);
+        let first = feed.publish(Content::Plain(Post::new("one")), 1.0).unwrap();
+        let second = feed
+            .publish_onto(Content::Plain(Post::new("two")), &first, 2.0)
+            .unwrap();
+
+        match feed.publish_onto(Content::Plain(Post::new("fork")), &first, 3.0) {
+            Err(Error::NotFeedTip { previous, tip }) => {
+                assert_eq!(previous, ParsedMessage::from_bytes(&first).unwrap().key);
+                assert_eq!(tip, ParsedMessage::from_bytes(&second).unwrap().key);
+            }
+            other => panic!("expected NotFeedTip, got {:?}", other),
+        }
+        assert_eq!(feed.latest().unwrap().sequence, 2);
+
+        let third = feed
+            .publish_onto(Content::Plain(Post::new("three")), &second, 3.0)
+            .unwrap();
+        assert!(validate_message_hash_chain(&third, Some(&second)).is_ok());
+    }
+
+    #[test]
+    fn feed_resumes_from_a_partial_tail() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed = Feed::new(pk, sk.clone());
+        let messages: Vec<Vec<u8>> = (1..=50)
+            .map(|i| {
+                feed.publish(Content::Plain(Post::new("hi")), i as f64)
+                    .unwrap()
+            })
+            .collect();
+
+        // Only sequence 50 was replicated.
+        let tail = &messages[49..];
+        let mut feed = Feed::from_partial_tail(tail, 50, pk, sk.clone()).unwrap();
+        assert_eq!(feed.trusted_from(), Some(50));
+        assert_eq!(feed.latest().unwrap().sequence, 50);
+
+        let next = feed.publish(Content::Plain(Post::new("51")), 51.0).unwrap();
+        assert!(validate_message_hash_chain(&next, Some(&messages[49])).is_ok());
+        let after = feed.publish(Content::Plain(Post::new("52")), 52.0).unwrap();
+        assert!(validate_message_hash_chain(&after, Some(&next)).is_ok());
+        assert_eq!(feed.latest().unwrap().sequence, 52);
+
+        match Feed::from_partial_tail(&messages[48..], 50, pk, sk) {
+            Err(Error::UnexpectedSequence { expected, actual }) => {
+                assert_eq!((expected, actual), (50, 49))
+            }
+            other => panic!("expected UnexpectedSequence, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Comparing the storage cost of a message in the encodings this crate supports.
+
+use snafu::ResultExt;
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_legacy_msg_data::value::Value;
+
+use crate::canonical::{self, CanonicalJson};
+use crate::{InvalidMessage, Result};
+
+/// The sizes of a message in each encoding, in bytes. See [footprint].
+///
+/// The encodings of features that are turned off have no size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footprint {
+    /// The size as legacy json, the way [publish](crate::publish) returns messages.
+    pub legacy_json: usize,
+    /// The size in the bipf encoding of ssb-db2, with the `db2` feature. See
+    /// [to_bipf](crate::to_bipf).
+    pub bipf: Option<usize>,
+    /// The size as [cbor](https://cbor.io), with the `cbor` feature.
+    pub cbor: Option<usize>,
+}
+
+/// The sizes of the `{key, value}` message `msg_bytes` in each encoding, eg. to compare the cost
+/// of storage backends.
+pub fn footprint(msg_bytes: &[u8]) -> Result<Footprint> {
+    let message = from_slice::<Value>(msg_bytes).context(InvalidMessage)?;
+
+    #[cfg(feature = "db2")]
+    let bipf = Some(crate::to_bipf(msg_bytes)?.len());
+    #[cfg(not(feature = "db2"))]
+    let bipf = None;
+
+    // Writing to a `Vec` can't fail, and every value has a cbor encoding.
+    #[cfg(feature = "cbor")]
+    let cbor = Some(serde_cbor::to_vec(&message).unwrap().len());
+    #[cfg(not(feature = "cbor"))]
+    let cbor = None;
+
+    Ok(Footprint {
+        legacy_json: canonical::DEFAULT.encode_value(&message)?.len(),
+        bipf,
+        cbor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{footprint, publish, Content, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    #[cfg(feature = "cbor")]
+    use ssb_legacy_msg_data::json::from_slice;
+    #[cfg(feature = "cbor")]
+    use ssb_legacy_msg_data::value::Value;
+
+    #[test]
+    fn footprint_has_every_encoding() {
+        let (pk, sk) = generate_longterm_keypair();
+        let msg = publish(
+            Content::Plain(Post::new("how big?")),
+            None::<&[u8]>,
+            &pk,
+            &sk,
+            0.0,
+        )
+        .unwrap();
+
+        let footprint = footprint(&msg).unwrap();
+        assert_eq!(footprint.legacy_json, msg.len());
+        assert_eq!(footprint.bipf.is_some(), cfg!(feature = "db2"));
+        assert_eq!(footprint.cbor.is_some(), cfg!(feature = "cbor"));
+        #[cfg(feature = "db2")]
+        assert_eq!(footprint.bipf, Some(crate::to_bipf(&msg).unwrap().len()));
+        #[cfg(feature = "cbor")]
+        {
+            let message = from_slice::<Value>(&msg).unwrap();
+            let cbor = serde_cbor::to_vec(&message).unwrap();
+            assert_eq!(footprint.cbor, Some(cbor.len()));
+        }
+    }
+}
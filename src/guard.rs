@@ -0,0 +1,142 @@
+//! Keeping storage append-only, whoever writes to it.
+
+use snafu::{ensure, ResultExt};
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{
+    BrokenFeedLink, InvalidMessage, NotFeedTip, ParsedMessage, PreviousSource, Result,
+    SsbPreviousMessage, UnexpectedSequence,
+};
+
+/// Storage that feeds can be appended to, such as a database or a log file.
+pub trait FeedStore: PreviousSource {
+    /// Store the `{key, value}` message `msg_bytes` as the new latest message of its feed.
+    fn append(&mut self, msg_bytes: &[u8]) -> Result<()>;
+}
+
+/// A [FeedStore] that only accepts messages continuing the latest stored message of their feed.
+///
+/// Every storage write goes through [AppendOnlyGuard::append], so a caller with a stale or wrong
+/// previous message can't fork a feed or leave a gap in it. Signatures and the rest of the
+/// message are not checked, see [validate_feed](crate::validate_feed) for that.
+#[derive(Debug, Clone, Default)]
+pub struct AppendOnlyGuard<S> {
+    store: S,
+}
+
+impl<S: FeedStore> AppendOnlyGuard<S> {
+    /// Guard `store`, trusting the messages already in it.
+    pub fn new(store: S) -> AppendOnlyGuard<S> {
+        AppendOnlyGuard { store }
+    }
+
+    /// The guarded store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Stop guarding the store and return it.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Append `msg_bytes` to the store if it is the next message of its feed.
+    ///
+    /// Fails with [Error::UnexpectedSequence](crate::Error::UnexpectedSequence) if the message
+    /// would leave a gap or repeat a sequence number, and with
+    /// [Error::NotFeedTip](crate::Error::NotFeedTip) or
+    /// [Error::BrokenFeedLink](crate::Error::BrokenFeedLink) if its `previous` is not the latest
+    /// stored message. Nothing is stored then.
+    pub fn append(&mut self, msg_bytes: &[u8]) -> Result<()> {
+        let message = from_slice::<SsbPreviousMessage>(msg_bytes).context(InvalidMessage)?;
+        let message = message.value;
+        let latest = self.store.latest(&message.author)?;
+
+        let expected = latest.as_ref().map_or(1, |latest| latest.sequence + 1);
+        ensure!(
+            message.sequence == expected,
+            UnexpectedSequence {
+                expected,
+                actual: message.sequence
+            }
+        );
+
+        match (message.previous, latest) {
+            (None, None) => (),
+            (Some(previous), Some(latest)) => ensure!(
+                previous == latest.key,
+                NotFeedTip {
+                    previous,
+                    tip: latest.key
+                }
+            ),
+            _ => {
+                return BrokenFeedLink {
+                    sequence: message.sequence,
+                }
+                .fail()
+            }
+        }
+
+        self.store.append(msg_bytes)
+    }
+}
+
+impl<S: FeedStore> PreviousSource for AppendOnlyGuard<S> {
+    fn latest(&self, author: &Multikey) -> Result<Option<ParsedMessage>> {
+        self.store.latest(author)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        publish, AppendOnlyGuard, Content, Error, MemorySource, ParsedMessage, Post, PreviousSource,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn guarded_store_rejects_forks_and_gaps() {
+        let (pk, sk) = generate_longterm_keypair();
+        let post = |text: &str| Content::Plain(Post::new(text));
+        let first = publish(post("1"), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let second = publish(post("2"), Some(&first), &pk, &sk, 1.0).unwrap();
+        let third = publish(post("3"), Some(&second), &pk, &sk, 2.0).unwrap();
+        let fork = publish(post("fork"), Some(&first), &pk, &sk, 3.0).unwrap();
+
+        let mut guard = AppendOnlyGuard::new(MemorySource::new());
+        match guard.append(&second) {
+            Err(Error::UnexpectedSequence { expected, actual }) => {
+                assert_eq!((expected, actual), (1, 2))
+            }
+            other => panic!("expected UnexpectedSequence, got {:?}", other),
+        }
+        guard.append(&first).unwrap();
+        match guard.append(&third) {
+            Err(Error::UnexpectedSequence { expected, actual }) => {
+                assert_eq!((expected, actual), (2, 3))
+            }
+            other => panic!("expected UnexpectedSequence, got {:?}", other),
+        }
+        guard.append(&second).unwrap();
+
+        match guard.append(&fork) {
+            Err(Error::UnexpectedSequence { expected, actual }) => {
+                assert_eq!((expected, actual), (3, 2))
+            }
+            other => panic!("expected UnexpectedSequence, got {:?}", other),
+        }
+        let fork = publish(post("fork"), Some(&fork), &pk, &sk, 4.0).unwrap();
+        match guard.append(&fork) {
+            Err(Error::NotFeedTip { tip, .. }) => {
+                assert_eq!(tip, ParsedMessage::from_bytes(&second).unwrap().key)
+            }
+            other => panic!("expected NotFeedTip, got {:?}", other),
+        }
+
+        guard.append(&third).unwrap();
+        let latest = guard.latest(&crate::author_of(&pk)).unwrap().unwrap();
+        assert_eq!(latest, ParsedMessage::from_bytes(&third).unwrap());
+    }
+}
@@ -0,0 +1,68 @@
+//! Hashing message values into message keys.
+
+use sha2::{Digest, Sha256};
+use ssb_multiformats::multihash::{Multihash, Target};
+
+use crate::node_buffer_binary_serializer;
+
+/// A way of hashing the signing encoding of a message value into its key.
+pub(crate) trait MessageHasher {
+    /// The name of the hash algorithm, as found in the `hash` field of every message.
+    ///
+    /// The legacy message encoder always writes "sha256", so that is the only name a hasher of
+    /// published messages can give.
+    fn algorithm(&self) -> &str;
+
+    /// The key of the message whose value encodes to `value_bytes`.
+    fn hash(&self, value_bytes: &[u8]) -> Multihash;
+}
+
+/// The hasher of classic ssb messages: sha256 of the value's "binary" (latin-1) encoding.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Sha256Hasher;
+
+/// The hasher used to publish.
+pub(crate) const DEFAULT: Sha256Hasher = Sha256Hasher;
+
+impl MessageHasher for Sha256Hasher {
+    fn algorithm(&self) -> &str {
+        "sha256"
+    }
+
+    fn hash(&self, value_bytes: &[u8]) -> Multihash {
+        let hashable_bytes =
+            node_buffer_binary_serializer(std::str::from_utf8(value_bytes).unwrap());
+        Multihash::from_sha256(Sha256::digest(&hashable_bytes).into(), Target::Message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::{MessageHasher, Sha256Hasher};
+    use crate::{publish, Content, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::json::from_slice;
+    use ssb_legacy_msg_data::value::Value;
+
+    #[test]
+    fn classic_hasher_writes_sha256() {
+        assert_eq!(Sha256Hasher.algorithm(), "sha256");
+
+        let (pk, sk) = generate_longterm_keypair();
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        let value = match from_slice::<Value>(&msg).unwrap() {
+            Value::Object(msg) => msg.get("value").unwrap().clone(),
+            _ => panic!("message is not an object"),
+        };
+        match value {
+            Value::Object(value) => {
+                assert_eq!(
+                    value.get("hash"),
+                    Some(&Value::String("sha256".to_string()))
+                )
+            }
+            _ => panic!("value is not an object"),
+        }
+    }
+}
@@ -2,17 +2,16 @@
 //!
 
 use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 //use ed25519_dalek::{Keypair, PublicKey, SecretKey, ExpandedSecretKey};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use snafu::{ensure, ResultExt, Snafu};
-use ssb_legacy_msg::Message;
-use ssb_legacy_msg_data::json::{from_slice, to_vec, DecodeJsonError};
-use ssb_legacy_msg_data::value::{Value, RidiculousStringMap};
+use snafu::{ensure, OptionExt, Snafu};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::json::{from_slice, DecodeJsonError};
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
 use ssb_legacy_msg_data::LegacyF64;
-use ssb_multiformats::multihash::{Target};
-use ssb_multiformats::multikey::{Multisig, Multikey};
-use ssb_crypto::{SecretKey, PublicKey, sign_detached};
+use ssb_multiformats::multibox::Multibox;
+use ssb_multiformats::multikey::{Multikey, Multisig};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -21,6 +20,23 @@ pub enum Error {
         source: DecodeJsonError,
         message: Vec<u8>,
     },
+    #[snafu(display("Previous message has no `{}` field", field))]
+    PreviousMessageMissingField { field: String },
+    #[snafu(display("Message was invalid. Decoding failed with: {}", source))]
+    InvalidMessage { source: DecodeJsonError },
+    #[snafu(display("`{}` is not a valid feed id", id))]
+    InvalidFeedId { id: String },
+    #[snafu(display("`{}` is not a message id", id))]
+    InvalidMessageId { id: String },
+    #[snafu(display(
+        "Signature suite `{}` does not match the key suite `{}`",
+        signature_suite,
+        key_suite
+    ))]
+    SignatureSuiteMismatch {
+        key_suite: String,
+        signature_suite: String,
+    },
     #[snafu(display("Invalid public key"))]
     InvalidPublicKey {},
     #[snafu(display("Invalid secret key"))]
@@ -29,27 +45,343 @@ pub enum Error {
     PreviousMessageAuthorIsIncorrect {},
     #[snafu(display("Legacy Json encoding failed with error"))]
     LegacyJsonEncodeFailed {},
+    #[snafu(display(
+        "Timestamp {} is more than {}ms ahead of the system clock",
+        timestamp,
+        max_future_skew
+    ))]
+    TimestampTooFarFuture {
+        timestamp: f64,
+        max_future_skew: u128,
+    },
+    #[snafu(display("Timestamp {} is not a valid legacy float", timestamp))]
+    InvalidTimestamp { timestamp: f64 },
+    #[snafu(display(
+        "Timestamp {}ms is out of range, it must be between 0 and {}",
+        ms,
+        timestamp::MAX_TIMESTAMP_MS
+    ))]
+    TimestampOutOfRange { ms: i64 },
+    #[snafu(display("Published message failed envelope validation: {}", reason))]
+    InvalidEnvelope { reason: String },
+    #[snafu(display("Content must serialize to a json object"))]
+    ContentNotObject {},
+    #[snafu(display("Content has the key `{}` more than once", key))]
+    DuplicateContentKey { key: String },
+    #[snafu(display("Content contains the control character {:?}", character))]
+    ControlCharInContent { character: char },
+    #[snafu(display("Content has no field `{}`", field))]
+    FieldNotFound { field: String },
+    #[snafu(display("Content is not encrypted"))]
+    ContentNotEncrypted {},
+    #[snafu(display("Decrypted content is not valid legacy json"))]
+    DecryptionFailed {},
+    #[snafu(display(
+        "Private messages must have between 1 and {} recipients, got {}",
+        private::MAX_RECIPIENTS,
+        count
+    ))]
+    InvalidRecipients { count: usize },
+    #[snafu(display(
+        "Feed message {} failed validation: {}",
+        validate::describe_message(sequence, key),
+        source
+    ))]
+    InvalidFeedMessage {
+        sequence: Option<u64>,
+        key: Option<String>,
+        #[snafu(source(from(ssb_validate::Error, Box::new)))]
+        source: Box<ssb_validate::Error>,
+    },
+    #[snafu(display(
+        "Feed message {} has an invalid signature: {}",
+        validate::describe_message(sequence, key),
+        source
+    ))]
+    InvalidFeedSignature {
+        sequence: Option<u64>,
+        key: Option<String>,
+        source: ssb_verify_signatures::Error,
+    },
+    #[snafu(display(
+        "Message {} of {} is invalid: {}",
+        sequence,
+        author.to_legacy_string(),
+        source
+    ))]
+    InvalidInterleavedMessage {
+        author: Multikey,
+        sequence: u64,
+        source: Box<Error>,
+    },
+    #[snafu(display(
+        "Message key {} does not match the hash of its value, {}",
+        key.to_legacy_string(),
+        actual.to_legacy_string()
+    ))]
+    KeyMismatch { key: Multihash, actual: Multihash },
+    #[snafu(display(
+        "Message is {} characters long, the limit is {}",
+        size,
+        MAX_MESSAGE_SIZE
+    ))]
+    MessageTooLarge { size: usize },
+    #[snafu(display(
+        "Previous message {} is not the latest message of the feed, {}",
+        previous.to_legacy_string(),
+        tip.to_legacy_string()
+    ))]
+    NotFeedTip { previous: Multihash, tip: Multihash },
+    #[snafu(display(
+        "Feed has {} messages, but its last message has sequence number {}",
+        length,
+        sequence
+    ))]
+    FeedLengthMismatch { length: usize, sequence: u64 },
+    #[snafu(display("Invalid bipf: {}", reason))]
+    InvalidBipf { reason: String },
+    #[snafu(display("Expected a message with sequence number {}, got {}", expected, actual))]
+    UnexpectedSequence { expected: u64, actual: u64 },
+    #[snafu(display("The feed tail has no messages"))]
+    EmptyFeedTail {},
+    #[snafu(display("Publishing too fast, retry in {}ms", retry_in.as_millis()))]
+    PublishingTooFast { retry_in: Duration },
+    #[snafu(display("Signing failed: {}", reason))]
+    SigningFailed { reason: String },
+    #[snafu(display("No keys for the feed {}", feed.to_legacy_string()))]
+    NoKeysForFeed { feed: Multikey },
+    #[snafu(display(
+        "Content refers to the message's own key with `{}`, which can't be known before \
+         publishing",
+        SELF_REFERENCE
+    ))]
+    SelfReferenceNotSupported {},
+    #[snafu(display("`{}` is not a reference in a known feed format", reference))]
+    UnknownRefFormat { reference: String },
+    #[snafu(display(
+        "Message {} does not link to the message before it in its feed",
+        sequence
+    ))]
+    BrokenFeedLink { sequence: u64 },
+    #[snafu(display(
+        "Difficulty {} is more than the {} bits a nonce search can reach",
+        difficulty,
+        max
+    ))]
+    DifficultyTooHigh { difficulty: u32, max: u32 },
+    #[snafu(display("No nonce gives a key with a difficulty of {} bits", difficulty))]
+    DifficultyNotReached { difficulty: u32 },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-pub use ssb_legacy_msg::Content;
+#[cfg(feature = "db2")]
+mod bipf;
+mod budget;
+mod canonical;
+mod content;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+mod envelope;
+mod feed;
+mod footprint;
+mod guard;
+mod hasher;
+mod manifest;
+mod migrate;
+#[cfg(feature = "pow")]
+mod pow;
+mod preview;
+mod private;
+mod publisher;
+mod queue;
+mod read;
+mod receipt;
+mod refs;
+mod relay;
+mod signer;
+mod source;
+mod stages;
+mod timestamp;
+mod timings;
+mod uri;
+mod validate;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
+use canonical::CanonicalJson;
+use timings::{Stage, StageTimer};
+
+#[cfg(feature = "db2")]
+pub use bipf::{from_bipf, to_bipf};
+pub use budget::FeedBudget;
+pub use content::{
+    blob_id, content_diff, feed_fingerprint, feed_ids_equal, publish_edit, publish_enveloped,
+    publish_post, publish_post_with_blob, About, Contact, ContentEnvelope, Mention, Post, PostEdit,
+    TypeTagged, Vote, VoteValue,
+};
+#[cfg(feature = "diagnostics")]
+pub use diagnostics::{publish_with_diagnostics, warnings_for, Warning};
+pub use envelope::{parse_signature, validate_envelope, validate_envelope_with_type_field};
+pub use feed::{Feed, FeedState};
+pub use footprint::{footprint, Footprint};
+pub use guard::{AppendOnlyGuard, FeedStore};
+pub use manifest::{publish_batch_with_manifest, Manifest, ManifestEntry};
+pub use migrate::migrate_feed;
+#[cfg(feature = "pow")]
+pub use pow::{pow_difficulty, publish_with_pow, MAX_DIFFICULTY};
+pub use preview::{preview_then_finish, Preview};
+pub use private::{
+    decrypt_field, encrypt_fields, encrypt_to_groups, publish_private, MAX_RECIPIENTS,
+};
+pub use publisher::Publisher;
+pub use queue::PublishQueue;
+pub use read::{
+    content_as, content_type, extract_content_bytes, extract_references, find_gaps,
+    is_after_checkpoint, is_encrypted, message_size, minimal_previous, recover_signable,
+    to_canonical_string, type_histogram, ParsedMessage, References, TypeHistogram,
+};
+pub use receipt::{publish_receipt, Receipt};
+pub use refs::{
+    is_valid_feed_ref, is_valid_msg_ref, ref_format, FeedFormat, ED25519_FEED_ID_LENGTH,
+};
+pub use relay::relay;
+pub use signer::{
+    publish_for, publish_with_signer, InProcessSigner, KeyStore, MemoryKeyStore, Signer,
+};
+pub use source::{publish_from_source, MemorySource, PreviousSource};
+pub use ssb_legacy_msg::{Content, Message};
 pub use ssb_multiformats::multihash::Multihash;
+pub use stages::{assemble_wrapper, build_message, compute_key, encode_signable, sign};
+pub use timestamp::{legacy_to_ms, ms_to_legacy, TimestampPolicy, MAX_TIMESTAMP_MS};
+#[cfg(feature = "timings")]
+pub use timings::{publish_timed, PublishTimings};
+pub use uri::{message_uri, publish_dual};
+pub use validate::{
+    validate_feed, validate_feed_with_report, validate_interleaved, verify_keys_match, FeedReport,
+};
+
+/// Optional checks performed by [publish_with_options].
+///
+/// The `Default` options are the ones used by [publish].
+#[derive(Debug, Clone)]
+pub struct PublishOptions {
+    /// Check the final `{key, value}` message with [validate_envelope] before returning it.
+    pub strict: bool,
+    /// Reject timestamps more than this far ahead of the system clock. Defaults to
+    /// [DEFAULT_MAX_FUTURE_SKEW].
+    ///
+    /// Set this to `None` when publishing deterministic or backfilled timestamps.
+    pub max_future_skew: Option<Duration>,
+    /// Check that the previous message was published by the same feed. Defaults to `true`.
+    ///
+    /// **Danger:** with this set to `false` the new message claims to continue someone else's
+    /// feed. Every other ssb implementation rejects such a message, and it can't be published to
+    /// the network. Only turn this off in repair or re-keying tools that knowingly build such
+    /// messages, and never publish their output as a normal feed.
+    pub check_previous_author: bool,
+    /// The order of the entries of plain content. Defaults to [ContentOrdering::Preserve].
+    ///
+    /// The order is part of the signed and hashed message, so changing it changes the message
+    /// key. Peers that re-encode content in a particular order need the same order here.
+    pub content_ordering: ContentOrdering,
+    /// Reject plain content that serializes a top-level key more than once. Defaults to `false`.
+    ///
+    /// Json parsers disagree on which of the duplicated entries wins, so such content reads
+    /// differently depending on who reads it. It is almost always a bug in a `Serialize` impl.
+    pub reject_duplicate_keys: bool,
+    /// Reject plain content with ascii control characters other than tab, newline and carriage
+    /// return in its strings or keys. Defaults to `false`.
+    ///
+    /// Such characters are valid json, but break some parsers and render badly, so apps that
+    /// publish user input may want to catch them.
+    pub reject_control_chars: bool,
+    /// The entry of plain content that holds its type. Defaults to [DEFAULT_TYPE_FIELD].
+    ///
+    /// Only for experimental content schemas with a different discriminator. The `type` entry of
+    /// content, such as the one the typed helpers like [Post] serialize, is renamed to this entry,
+    /// which is also the one [IndexEntry::content_type] is read from and [strict](Self::strict)
+    /// validation requires. Other ssb implementations only know `type`.
+    pub type_field: String,
+}
+
+/// The order in which the entries of plain content objects are published.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentOrdering {
+    /// The order in which the content serializes its fields.
+    Preserve,
+    /// Sorted by key, recursively in nested objects.
+    Sorted,
+}
+
+/// A placeholder for the key of the message being published, which content can't refer to.
+///
+/// The key is the hash of the whole message, content included, so it can't be known while the
+/// content is written. Publishing fails with [Error::SelfReferenceNotSupported] if any string in
+/// plain content is this placeholder, rather than publishing a reference to nothing.
+pub const SELF_REFERENCE: &str = "%self";
+
+/// The entry of content objects that holds their type in ssb.
+pub const DEFAULT_TYPE_FIELD: &str = "type";
+
+/// The maximum size of a message value, counted the way [message_size] does.
+pub const MAX_MESSAGE_SIZE: usize = 8192;
+
+/// How far ahead of the system clock a timestamp may be by default, 15 minutes.
+pub const DEFAULT_MAX_FUTURE_SKEW: Duration = Duration::from_secs(15 * 60);
+
+impl Default for PublishOptions {
+    fn default() -> PublishOptions {
+        PublishOptions {
+            strict: false,
+            max_future_skew: Some(DEFAULT_MAX_FUTURE_SKEW),
+            check_previous_author: true,
+            content_ordering: ContentOrdering::Preserve,
+            reject_duplicate_keys: false,
+            reject_control_chars: false,
+            type_field: DEFAULT_TYPE_FIELD.to_owned(),
+        }
+    }
+}
+
+/// Metadata about a published message, for building indexes. Returned by [publish_indexed].
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+    /// The feed that published the message.
+    pub author: Multikey,
+    /// The position of the message in its feed, starting at 1.
+    pub sequence: u64,
+    /// The claimed publishing time, in milliseconds since the unix epoch.
+    pub timestamp: LegacyF64,
+    /// The `type` of the content, or `None` if the content is encrypted.
+    pub content_type: Option<String>,
+    /// The message key.
+    pub key: Multihash,
+}
 
 /// Publish a new message.
 ///
 /// - Bring your own ed25519 keys (as bytes)
-/// - You choose whether or not to publish actual timestamps or just random numbers.
+/// - You choose whether or not to publish actual timestamps or just random numbers. Timestamps
+///   more than [DEFAULT_MAX_FUTURE_SKEW] ahead of the system clock are rejected, see
+///   [PublishOptions] to change this.
+/// - Messages longer than [MAX_MESSAGE_SIZE] once signed and encoded are rejected.
 ///
-/// Returns a tuple of: 
+/// Returns a tuple of:
 /// - the new message as a Vec of bytes. This is the message value with keys `previous`, `sequence`,
-/// `content` etc.
-/// - the [Multihash] (ssb message key) of the new message 
+///   `content` etc.
+/// - the [Multihash] (ssb message key) of the new message
 ///
-/// You may use this to publish public _or_ private messages. 
+/// You may use this to publish public _or_ private messages.
 /// If you want to publish private messages, you'll have to encrypt them first and wrap them in
 /// the `Content::Encrypted` enum variant.  
-/// 
+///
+/// The supported `Content` variants are:
+/// - `Content::Plain`, content that serializes to a json object with a `type` field. Anything else
+///   fails with [Error::ContentNotObject].
+/// - `Content::Encrypted`, an already encrypted private box.
+///
 /// ## Example
 ///
 ///```
@@ -97,92 +429,535 @@ pub use ssb_multiformats::multihash::Multihash;
 ///  assert!(is_valid);
 ///  assert!(is_verified);
 ///  ```
+pub fn publish<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    publish_with_options(
+        content,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+        &PublishOptions::default(),
+    )
+}
 
+/// Publish a new message, like [publish], with the optional checks in `options`.
+pub fn publish_with_options<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+    options: &PublishOptions,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
 
-pub fn publish<T, P>(
+    publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        options,
+    )
+    .map(|(message, _)| message)
+}
+
+/// Publish a new message, like [publish], also returning an [IndexEntry] describing it.
+///
+/// The entry is built from data already at hand while publishing, so the message doesn't need to
+/// be parsed again to index it.
+pub fn publish_indexed<T, P>(
     content: Content<T>,
     previous_msg_value_bytes: Option<P>,
     public_key: &PublicKey,
     secret_key: &SecretKey,
     timestamp: f64,
+) -> Result<(Vec<u8>, IndexEntry)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+    )
+}
+
+/// Publish a new message after an already parsed `previous` message, like [publish].
+///
+/// Use this when `previous` has already been parsed, eg. while validating it, to save parsing it
+/// again.
+pub fn publish_with_parsed_previous<T: Serialize>(
+    content: Content<T>,
+    previous: &ParsedMessage,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>> {
+    publish_parsed(
+        content,
+        Some(previous),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+    )
+    .map(|(message, _)| message)
+}
+
+/// Publish a new message, like [publish], with an already valid `LegacyF64` timestamp.
+///
+/// Unlike [publish], this can't fail converting the timestamp.
+pub fn publish_with_legacy_timestamp<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: LegacyF64,
 ) -> Result<Vec<u8>>
 where
     T: Serialize,
-    P: AsRef<[u8]>
+    P: AsRef<[u8]>,
 {
+    let previous = parse_previous(previous_msg_value_bytes)?;
 
-    let author = Multikey::from_ed25519(public_key.as_ref().try_into().unwrap());
+    publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        timestamp,
+        &PublishOptions::default(),
+    )
+    .map(|(message, _)| message)
+}
 
-    let previous_message = match previous_msg_value_bytes {
-        Some(message) => {
-            let message = message.as_ref();
-            let decoded_previous =
-                from_slice::<SsbPreviousMessage>(message).context(InvalidPreviousMessage {
-                    message: message.to_owned(),
-                })?;
-            Some(decoded_previous)
-        }
-        None => None,
+/// Publish a new message, like [publish], with content that is already encoded as json, such as
+/// the content [extract_content_bytes] takes out of another message.
+///
+/// `content_bytes` must be a json object, or a json string holding encrypted content.
+pub fn publish_raw_content<P: AsRef<[u8]>>(
+    content_bytes: &[u8],
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>> {
+    let content = match canonical::DEFAULT.decode_value(content_bytes)? {
+        Value::String(boxed) => match Multibox::from_legacy(boxed.as_bytes()) {
+            Ok((boxed, [])) => Content::Encrypted(boxed),
+            _ => return ContentNotObject.fail(),
+        },
+        content => Content::Plain(content),
     };
 
-    let (new_seq, previous_key, previous_author) = previous_message
-        .map(|msg| (msg.value.sequence + 1, Some(msg.key), Some(msg.value.author)))
-        .unwrap_or((1, None, None));
+    publish(
+        content,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )
+}
+
+/// Publish a new message, like [publish], building its content only once it is needed.
+///
+/// `content` is not called if publishing fails before the content is used: if the previous
+/// message can't be parsed or was published by another feed, or if the timestamp is invalid or
+/// too far in the future.
+pub fn publish_lazy<T, F, P>(
+    content: F,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    F: FnOnce() -> Content<T>,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    publish_lazy_timed(
+        content,
+        previous.as_ref(),
+        &InProcessSigner::new(*public_key, secret_key.clone()),
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+        &mut (),
+    )
+    .map(|(message, _)| message)
+}
+
+fn author_of(public_key: &PublicKey) -> Multikey {
+    Multikey::from_ed25519(public_key.as_ref().try_into().unwrap())
+}
+
+pub(crate) fn legacy_timestamp(timestamp: f64) -> Result<LegacyF64> {
+    LegacyF64::from_f64(timestamp).context(InvalidTimestamp { timestamp })
+}
+
+// The previous message passed to the `publish` functions, parsed if there is one.
+pub(crate) fn parse_previous<P: AsRef<[u8]>>(
+    previous_msg_value_bytes: Option<P>,
+) -> Result<Option<ParsedMessage>> {
+    previous_msg_value_bytes
+        .map(|message| ParsedMessage::from_bytes(message.as_ref()))
+        .transpose()
+}
+
+// The checks of `publish_parsed` that don't need the content.
+pub(crate) fn check_before_content(
+    previous: Option<&ParsedMessage>,
+    public_key: &PublicKey,
+    timestamp: LegacyF64,
+    options: &PublishOptions,
+) -> Result<()> {
+    let author = author_of(public_key);
+    let previous_author = previous.map(|msg| &msg.author);
 
     // Make sure the author of the previous message matches the public key we're using to publish
     // with.
     if let Some(previous_author) = previous_author {
-        ensure!(previous_author == author, PreviousMessageAuthorIsIncorrect)
+        ensure!(
+            !options.check_previous_author || *previous_author == author,
+            PreviousMessageAuthorIsIncorrect
+        )
     }
 
-    let mut new_message = Message::<T> {
+    if let Some(max_future_skew) = options.max_future_skew {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        ensure!(
+            f64::from(timestamp) <= (now + max_future_skew).as_millis() as f64,
+            TimestampTooFarFuture {
+                timestamp: f64::from(timestamp),
+                max_future_skew: max_future_skew.as_millis()
+            }
+        );
+    }
+
+    Ok(())
+}
+
+fn publish_parsed<T: Serialize>(
+    content: Content<T>,
+    previous: Option<&ParsedMessage>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: LegacyF64,
+    options: &PublishOptions,
+) -> Result<(Vec<u8>, IndexEntry)> {
+    publish_parsed_timed(
         content,
-        author,
-        previous: previous_key,
-        sequence: new_seq,
-        swapped: false,
-        timestamp: LegacyF64::from_f64(timestamp).unwrap(),
-        signature: None, // We'll generate the signature below.
+        previous,
+        &InProcessSigner::new(*public_key, secret_key.clone()),
+        timestamp,
+        options,
+        &mut (),
+    )
+}
+
+// `publish_parsed`, reporting how long each stage takes to `timer`.
+fn publish_parsed_timed<T: Serialize, S: StageTimer>(
+    content: Content<T>,
+    previous: Option<&ParsedMessage>,
+    signer: &dyn Signer,
+    timestamp: LegacyF64,
+    options: &PublishOptions,
+    timer: &mut S,
+) -> Result<(Vec<u8>, IndexEntry)> {
+    publish_lazy_timed(|| content, previous, signer, timestamp, options, timer)
+}
+
+// `publish_parsed_timed`, building the content only once the checks that don't need it pass.
+fn publish_lazy_timed<T, F, S>(
+    content: F,
+    previous: Option<&ParsedMessage>,
+    signer: &dyn Signer,
+    timestamp: LegacyF64,
+    options: &PublishOptions,
+    timer: &mut S,
+) -> Result<(Vec<u8>, IndexEntry)>
+where
+    T: Serialize,
+    F: FnOnce() -> Content<T>,
+    S: StageTimer,
+{
+    let public_key = signer.public_key();
+    check_before_content(previous, public_key, timestamp, options)?;
+
+    let content = checked_content(content(), options)?;
+
+    let mut new_message = build_message(content, previous, public_key, timestamp);
+    let signable_bytes = timer.time(Stage::Encode, || encode_signable(&new_message))?;
+    let signature = timer.time(Stage::Sign, || signer.sign(&signable_bytes))?;
+    new_message.signature = Some(Multisig::from_ed25519(&signature));
+    let published_bytes = timer.time(Stage::Encode, || encode_signable(&new_message))?;
+
+    let size = legacy_length(&published_bytes);
+    ensure!(size <= MAX_MESSAGE_SIZE, MessageTooLarge { size });
+
+    let key = timer.time(Stage::Hash, || compute_key(&published_bytes));
+    let value = canonical::DEFAULT.decode_value(&published_bytes)?;
+
+    let content = match &value {
+        Value::Object(value) => value.get("content"),
+        _ => None,
+    };
+    let content_type = match content {
+        Some(Value::Object(content)) => match content.get(&options.type_field) {
+            Some(Value::String(content_type)) => Some(content_type.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+    let entry = IndexEntry {
+        author: new_message.author,
+        sequence: new_message.sequence,
+        timestamp: new_message.timestamp,
+        content_type,
+        key: key.clone(),
     };
 
-    let signable_bytes = ssb_legacy_msg::json::to_legacy_vec(&new_message, false)
-        .map_err(|_| snafu::NoneError)
-        .context(LegacyJsonEncodeFailed)?;
+    let message_bytes = timer.time(Stage::Encode, || stages::wrap(&key, value))?;
 
-    let mut sig = [0; 64];
+    if options.strict {
+        validate_envelope_with_type_field(&message_bytes, &options.type_field)?;
+    }
 
-    let signature_bytes = sign_detached(&signable_bytes, secret_key);
+    debug_assert!(
+        from_slice::<SsbMessage>(&message_bytes).is_ok(),
+        "published message does not decode as an `SsbMessage`"
+    );
+    // Other implementations check these ids against the exact form `ssb-ref` accepts.
+    debug_assert_eq!(
+        entry.author.to_legacy_string().len(),
+        ED25519_FEED_ID_LENGTH
+    );
+    debug_assert!(is_valid_feed_ref(&entry.author.to_legacy_string()));
+    debug_assert!(is_valid_msg_ref(&entry.key.to_legacy_string()));
+    debug_assert!(previous
+        .into_iter()
+        .all(|msg| is_valid_msg_ref(&msg.key.to_legacy_string())));
 
-    signature_bytes
-        .as_ref()
-        .iter()
-        .enumerate()
-        .for_each(|(i, byte)| sig[i] = *byte);
+    Ok((message_bytes, entry))
+}
 
-    let signature = Multisig::from_ed25519(&sig);
+/// Validate an existing feed, then publish a new message onto its end.
+///
+/// `messages` must yield every `{key, value}` message of the feed in order, starting from its
+/// first message. Publishing fails if any of them is invalid (see [validate_feed]), or if the feed
+/// was not authored by `public_key`.
+pub fn resume_and_publish<T, I>(
+    messages: I,
+    content: Content<T>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let tail = validate_feed(messages)?;
+    publish(content, tail, public_key, secret_key, timestamp)
+}
 
-    new_message.signature = Some(signature);
+/// Publish a new message after the last message of `history`.
+///
+/// `history` must yield the `{key, value}` messages of the feed in order, starting from its first
+/// message. It is consumed one message at a time, checking that each links to the one before it.
+/// Unlike [resume_and_publish], signatures are not verified, so use this for history that was
+/// already verified when it was stored.
+pub fn publish_after<T, I>(
+    content: Content<T>,
+    history: I,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut last: Option<I::Item> = None;
 
-    let published_bytes = ssb_legacy_msg::json::to_legacy_vec(&new_message, false).unwrap();
+    for message in history {
+        validate::check_link(message.as_ref(), last.as_ref().map(AsRef::as_ref))?;
+        last = Some(message);
+    }
 
-    let key = get_multihash_from_message_bytes(&published_bytes);
-    let value = from_slice(&published_bytes).unwrap();  
+    publish(content, last, public_key, secret_key, timestamp)
+}
 
-    let mut map = RidiculousStringMap::with_capacity(1);
-    map.insert("key".to_owned(), Value::String(key.to_legacy_string()));
-    map.insert("value".to_owned(), value);
-    let message: Value = Value::Object(map);
+/// Publish a new message onto the end of `feed`, an in-memory feed, and push it onto `feed`.
+///
+/// `feed` must hold every `{key, value}` message of the feed in order, starting from its first
+/// message. Only its last message is parsed, and publishing fails with
+/// [Error::FeedLengthMismatch] unless its sequence number is the length of `feed`, which catches
+/// feeds with missing messages. Returns the [IndexEntry] of the new message.
+pub fn publish_appending<T: Serialize>(
+    content: Content<T>,
+    feed: &mut Vec<Vec<u8>>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<IndexEntry> {
+    let previous = parse_previous(feed.last())?;
+    if let Some(previous) = &previous {
+        ensure!(
+            previous.sequence == feed.len() as u64,
+            FeedLengthMismatch {
+                length: feed.len(),
+                sequence: previous.sequence
+            }
+        );
+    }
 
-    let message_bytes = to_vec(&message, false).unwrap();
+    let (message, entry) = publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+    )?;
+    feed.push(message);
+    Ok(entry)
+}
+
+/// How much content fits in the next message of the feed of `public_key`, for showing how many
+/// characters remain while composing it.
+///
+/// This is [MAX_MESSAGE_SIZE] less the rest of the message, counted the same way: the content is
+/// measured as it is encoded in the message, in utf-16 code units after escaping and including
+/// its indentation.
+pub fn content_budget<P: AsRef<[u8]>>(
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    timestamp: f64,
+) -> Result<usize> {
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    let empty = Content::Plain(Value::Object(RidiculousStringMap::with_capacity(0)));
+    let timestamp = legacy_timestamp(timestamp)?;
+    let mut message = build_message(empty, previous.as_ref(), public_key, timestamp);
+    // Every signature encodes to the same length, so a placeholder stands in for the real one.
+    message.signature = Some(Multisig::from_ed25519(&[0; 64]));
+    let bytes = encode_signable(&message)?;
+    // Less the empty content, `{}`.
+    let envelope = legacy_length(&bytes) - 2;
 
-    Ok(message_bytes)
+    Ok(MAX_MESSAGE_SIZE.saturating_sub(envelope))
 }
 
-fn get_multihash_from_message_bytes(bytes: &[u8]) -> Multihash {
-    let hashable_bytes = node_buffer_binary_serializer(&std::str::from_utf8(bytes).unwrap());
-    let hash = Sha256::digest(&hashable_bytes);
-    Multihash::from_sha256(hash.into(), Target::Message)
+// `content` as it is published with `options`, once it passes the checks that apply to it.
+pub(crate) fn checked_content<T: Serialize>(
+    content: Content<T>,
+    options: &PublishOptions,
+) -> Result<Content<Value>> {
+    // Handle every `Content` variant explicitly, so that a variant added upstream fails to compile
+    // here instead of being published without anyone deciding how it should be.
+    match content {
+        Content::Plain(plain) => {
+            let plain = plain_content(&plain, options)?;
+            check_content(&plain, options)?;
+            Ok(Content::Plain(plain))
+        }
+        Content::Encrypted(boxed) => Ok(Content::Encrypted(boxed)),
+    }
+}
+
+// Plain `content` as it is published with `options`.
+fn plain_content<T: Serialize>(content: &T, options: &PublishOptions) -> Result<Value> {
+    // Checked before converting to a `Value`, which keeps only one entry per key.
+    if options.reject_duplicate_keys {
+        if let Some(key) = canonical::duplicate_key(content)? {
+            return DuplicateContentKey { key }.fail();
+        }
+    }
+
+    let mut content = content::to_value(content)?;
+    if options.type_field != DEFAULT_TYPE_FIELD {
+        if let Some(retagged) = content::retag(&content, &options.type_field) {
+            content = retagged;
+        }
+    }
+    if options.content_ordering == ContentOrdering::Sorted {
+        content = canonical::sort_keys(&content);
+    }
+    Ok(content)
+}
+
+// The checks of `publish_parsed` on plain `content`, run before it is signed.
+fn check_content(content: &Value, options: &PublishOptions) -> Result<()> {
+    // Plain content must be an object, only encrypted content is a (boxed) string.
+    ensure!(matches!(content, Value::Object(_)), ContentNotObject);
+    ensure!(!has_self_reference(content), SelfReferenceNotSupported);
+    if options.reject_control_chars {
+        if let Some(character) = find_control_char(content) {
+            return ControlCharInContent { character }.fail();
+        }
+    }
+    Ok(())
+}
+
+// The length javascript gives the encoded value, in utf-16 code units. Escaping is already part of
+// the encoding, so this is exact.
+pub(crate) fn legacy_length(bytes: &[u8]) -> usize {
+    std::str::from_utf8(bytes).unwrap().encode_utf16().count()
+}
+
+// The first ascii control character in the strings or keys of `value`, other than whitespace.
+fn find_control_char(value: &Value) -> Option<char> {
+    let is_control = |c: &char| c.is_ascii_control() && !matches!(c, '\t' | '\n' | '\r');
+    match value {
+        Value::String(s) => s.chars().find(is_control),
+        Value::Array(values) => values.iter().find_map(find_control_char),
+        Value::Object(map) => map.iter().find_map(|(key, value)| {
+            key.chars()
+                .find(is_control)
+                .or_else(|| find_control_char(value))
+        }),
+        _ => None,
+    }
 }
+
+// Whether any string in `value` is the `SELF_REFERENCE` placeholder.
+fn has_self_reference(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s == SELF_REFERENCE,
+        Value::Array(values) => values.iter().any(has_self_reference),
+        Value::Object(map) => map.iter().any(|(_, value)| has_self_reference(value)),
+        _ => false,
+    }
+}
+
 fn node_buffer_binary_serializer(text: &str) -> Vec<u8> {
     text.encode_utf16()
         .map(|word| (word & 0xFF) as u8)
@@ -206,27 +981,31 @@ struct SsbMessage {
     key: Multihash,
     value: Value,
 }
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-#[serde(rename = "contact")]
-struct Contact {
-    contact: Multikey,
-    following: bool,
-    blocking: bool,
-}
 
 #[cfg(test)]
 mod tests {
-    use crate::{publish, Contact, Content};
+    use crate::test_utils::example_contact;
+    use crate::{
+        content_as, content_budget, encrypt_to_groups, extract_content_bytes, message_size,
+        publish, publish_after, publish_appending, publish_indexed, publish_lazy,
+        publish_raw_content, publish_with_legacy_timestamp, publish_with_options,
+        publish_with_parsed_previous, resume_and_publish, validate_envelope,
+        validate_envelope_with_type_field, validate_feed, About, Contact, Content, ContentOrdering,
+        Error, Mention, Multihash, ParsedMessage, Post, PublishOptions, SsbMessage, Vote,
+        VoteValue, MAX_MESSAGE_SIZE, SELF_REFERENCE,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::json::from_slice;
+    use ssb_legacy_msg_data::value::Value;
+    use ssb_legacy_msg_data::LegacyF64;
     use ssb_multiformats::multikey::Multikey;
     use ssb_validate::validate_message_hash_chain;
     use ssb_verify_signatures::verify_message;
-    use ssb_crypto::{generate_longterm_keypair};
-
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     #[test]
     fn it_works() {
-        let (pk, sk) =  generate_longterm_keypair();
+        let (pk, sk) = generate_longterm_keypair();
 
         let contact = Contact {
             contact: Multikey::from_legacy(
@@ -238,14 +1017,7 @@ mod tests {
             blocking: false,
         };
         let content = Content::Plain(contact);
-        let msg1 = publish::<_, &[u8]>(
-            content,
-            None,
-            &pk,
-            &sk,
-            0.0,
-        )
-        .unwrap();
+        let msg1 = publish::<_, &[u8]>(content, None, &pk, &sk, 0.0).unwrap();
 
         let is_valid1 = validate_message_hash_chain::<_, &[u8]>(&msg1, None).is_ok();
         let is_verified1 = verify_message(&msg1).is_ok();
@@ -260,21 +1032,683 @@ mod tests {
             blocking: false,
         };
         let content = Content::Plain(contact);
+        let msg2 = publish(content, Some(&msg1), &pk, &sk, 0.0).unwrap();
+
+        let is_valid2 = validate_message_hash_chain(&msg2, Some(&msg1)).is_ok();
+        let is_verified2 = verify_message(&msg2).is_ok();
+
+        assert!(is_valid1);
+        assert!(is_verified1);
+        assert!(is_valid2);
+        assert!(is_verified2);
+    }
+
+    #[test]
+    fn strict_mode_validates_envelope() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let contact = example_contact(true);
+        let options = PublishOptions {
+            strict: true,
+            ..PublishOptions::default()
+        };
+        let msg = publish_with_options::<_, &[u8]>(
+            Content::Plain(contact),
+            None,
+            &pk,
+            &sk,
+            0.0,
+            &options,
+        )
+        .unwrap();
+
+        assert!(validate_envelope(&msg).is_ok());
+
+        let corrupted = String::from_utf8(msg)
+            .unwrap()
+            .replace(r#""hash": "sha256""#, r#""hash": "md5""#);
+
+        match validate_envelope(corrupted.as_bytes()) {
+            Err(Error::InvalidEnvelope { .. }) => (),
+            other => panic!("expected an invalid envelope, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resume_validates_before_publishing() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let msg1 = publish::<_, &[u8]>(Content::Plain(example_contact(true)), None, &pk, &sk, 0.0)
+            .unwrap();
         let msg2 = publish(
-            content,
+            Content::Plain(example_contact(false)),
+            Some(&msg1),
+            &pk,
+            &sk,
+            1.0,
+        )
+        .unwrap();
+
+        let msg3 = resume_and_publish(
+            vec![&msg1, &msg2],
+            Content::Plain(example_contact(true)),
+            &pk,
+            &sk,
+            2.0,
+        )
+        .unwrap();
+        assert!(validate_message_hash_chain(&msg3, Some(&msg2)).is_ok());
+        assert!(verify_message(&msg3).is_ok());
+
+        let tampered = String::from_utf8(msg2.clone())
+            .unwrap()
+            .replace(r#""following": false"#, r#""following": true"#);
+
+        match resume_and_publish(
+            vec![msg1.as_slice(), tampered.as_bytes()],
+            Content::Plain(example_contact(true)),
+            &pk,
+            &sk,
+            2.0,
+        ) {
+            Err(Error::InvalidFeedMessage { .. }) => (),
+            other => panic!("expected an invalid feed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publish_with_parsed_previous_matches_publish() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let msg1 = publish::<_, &[u8]>(Content::Plain(example_contact(true)), None, &pk, &sk, 0.0)
+            .unwrap();
+        let parsed = ParsedMessage::from_bytes(&msg1).unwrap();
+        assert_eq!(parsed.sequence, 1);
+
+        let from_parsed = publish_with_parsed_previous(
+            Content::Plain(example_contact(false)),
+            &parsed,
+            &pk,
+            &sk,
+            1.0,
+        );
+        let from_bytes = publish(
+            Content::Plain(example_contact(false)),
             Some(&msg1),
             &pk,
             &sk,
+            1.0,
+        );
+
+        assert_eq!(from_parsed.unwrap(), from_bytes.unwrap());
+    }
+
+    #[test]
+    fn index_entry_matches_message() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let contact = example_contact(true);
+        let (msg, entry) =
+            publish_indexed::<_, &[u8]>(Content::Plain(contact), None, &pk, &sk, 42.0).unwrap();
+        let parsed = ParsedMessage::from_bytes(&msg).unwrap();
+
+        assert_eq!(entry.key, parsed.key);
+        assert_eq!(entry.author, parsed.author);
+        assert_eq!(entry.sequence, parsed.sequence);
+        assert_eq!(entry.timestamp, parsed.timestamp);
+        assert_eq!(entry.content_type, Some("contact".to_string()));
+    }
+
+    #[test]
+    fn rejects_timestamps_far_in_the_future() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64;
+        let in_an_hour = now + 60.0 * 60.0 * 1000.0;
+
+        match publish::<_, &[u8]>(
+            Content::Plain(example_contact(true)),
+            None,
+            &pk,
+            &sk,
+            in_an_hour,
+        ) {
+            Err(Error::TimestampTooFarFuture { .. }) => (),
+            other => panic!("expected a future timestamp error, got {:?}", other),
+        }
+
+        assert!(
+            publish::<_, &[u8]>(Content::Plain(example_contact(true)), None, &pk, &sk, now).is_ok()
+        );
+
+        let backfill = PublishOptions {
+            max_future_skew: None,
+            ..PublishOptions::default()
+        };
+        assert!(publish_with_options::<_, &[u8]>(
+            Content::Plain(example_contact(true)),
+            None,
+            &pk,
+            &sk,
+            in_an_hour,
+            &backfill
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn publish_with_a_legacy_timestamp() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let timestamp = LegacyF64::from_f64(1571140551485.0).unwrap();
+
+        let msg = publish_with_legacy_timestamp::<_, &[u8]>(
+            Content::Plain(example_contact(true)),
+            None,
+            &pk,
+            &sk,
+            timestamp,
+        )
+        .unwrap();
+        assert_eq!(
+            ParsedMessage::from_bytes(&msg).unwrap().timestamp,
+            timestamp
+        );
+        assert!(validate_message_hash_chain::<_, &[u8]>(&msg, None).is_ok());
+
+        match publish::<_, &[u8]>(
+            Content::Plain(example_contact(true)),
+            None,
+            &pk,
+            &sk,
+            f64::NAN,
+        ) {
+            Err(Error::InvalidTimestamp { .. }) => (),
+            other => panic!("expected an invalid timestamp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_content_must_be_an_object() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        match publish::<_, &[u8]>(Content::Plain(42), None, &pk, &sk, 0.0) {
+            Err(Error::ContentNotObject {}) => (),
+            other => panic!("expected the content to be rejected, got {:?}", other),
+        }
+        match publish::<_, &[u8]>(Content::Plain(vec!["post"]), None, &pk, &sk, 0.0) {
+            Err(Error::ContentNotObject {}) => (),
+            other => panic!("expected the content to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publish_after_an_iterator_of_history() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for i in 0..3 {
+            let post = Content::Plain(Post::new(format!("{}", i)));
+            feed.push(publish_after(post, feed.iter(), &pk, &sk, i as f64).unwrap());
+        }
+        for (i, msg) in feed.iter().enumerate() {
+            let previous = if i == 0 { None } else { Some(&feed[i - 1]) };
+            assert!(validate_message_hash_chain(msg, previous).is_ok());
+        }
+
+        // History with a message missing doesn't link up.
+        let broken = vec![&feed[0], &feed[2]];
+        match publish_after(Content::Plain(Post::new("3")), broken, &pk, &sk, 3.0) {
+            Err(Error::InvalidFeedMessage { .. }) => (),
+            other => panic!("expected the history to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn previous_author_check_can_be_disabled() {
+        let (old_pk, old_sk) = generate_longterm_keypair();
+        let (new_pk, new_sk) = generate_longterm_keypair();
+
+        let msg1 = publish::<_, &[u8]>(
+            Content::Plain(Post::new("one")),
+            None,
+            &old_pk,
+            &old_sk,
             0.0,
         )
         .unwrap();
 
-        let is_valid2 = validate_message_hash_chain(&msg2, Some(&msg1)).is_ok();
-        let is_verified2 = verify_message(&msg2).is_ok();
+        let post = Content::Plain(Post::new("two"));
+        match publish(post, Some(&msg1), &new_pk, &new_sk, 1.0) {
+            Err(Error::PreviousMessageAuthorIsIncorrect {}) => (),
+            other => panic!("expected the author to be rejected, got {:?}", other),
+        }
 
-        assert!(is_valid1);
-        assert!(is_verified1);
-        assert!(is_valid2);
-        assert!(is_verified2);
+        let options = PublishOptions {
+            check_previous_author: false,
+            ..PublishOptions::default()
+        };
+        let post = Content::Plain(Post::new("two"));
+        let msg2 =
+            publish_with_options(post, Some(&msg1), &new_pk, &new_sk, 1.0, &options).unwrap();
+        assert_eq!(ParsedMessage::from_bytes(&msg2).unwrap().sequence, 2);
+        assert!(verify_message(&msg2).is_ok());
+    }
+
+    #[test]
+    fn output_roundtrips_through_ssb_message() {
+        let (pk, sk) = generate_longterm_keypair();
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+
+        let decoded = from_slice::<SsbMessage>(&msg).unwrap();
+        assert_eq!(decoded.key, ParsedMessage::from_bytes(&msg).unwrap().key);
+        assert_eq!(
+            ssb_legacy_msg_data::json::to_vec(&decoded, false).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn sorted_content_ordering_changes_the_key() {
+        let (pk, sk) = generate_longterm_keypair();
+        let content = br#"{"type": "post", "text": "hi", "b": {"d": 1, "c": 2}}"#;
+        let content = from_slice::<Value>(content).unwrap();
+        let publish_ordered = |content_ordering| {
+            let options = PublishOptions {
+                content_ordering,
+                ..PublishOptions::default()
+            };
+            let content = Content::Plain(content.clone());
+            publish_with_options::<_, &[u8]>(content, None, &pk, &sk, 0.0, &options).unwrap()
+        };
+
+        let default =
+            publish::<_, &[u8]>(Content::Plain(content.clone()), None, &pk, &sk, 0.0).unwrap();
+        let preserved = publish_ordered(ContentOrdering::Preserve);
+        let sorted = publish_ordered(ContentOrdering::Sorted);
+
+        assert_eq!(preserved, default);
+        assert_ne!(
+            ParsedMessage::from_bytes(&sorted).unwrap().key,
+            ParsedMessage::from_bytes(&preserved).unwrap().key
+        );
+        assert!(verify_message(&sorted).is_ok());
+
+        let sorted = String::from_utf8(sorted).unwrap();
+        let keys: Vec<_> = ["\"b\"", "\"c\"", "\"d\"", "\"text\"", "\"type\""]
+            .iter()
+            .map(|key| sorted.find(key).unwrap())
+            .collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn content_budget_is_exact() {
+        let (pk, sk) = generate_longterm_keypair();
+        let first =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+
+        let budget = content_budget(Some(&first), &pk, 1.0).unwrap();
+        // `Post::new("")` takes 40 units encoded in the message value:
+        // {
+        //     "type": "post",
+        //     "text": ""
+        //   }
+        let fits = "a".repeat(budget - 40);
+
+        let post = Content::Plain(Post::new(fits.clone()));
+        let msg = publish(post, Some(&first), &pk, &sk, 1.0).unwrap();
+        assert_eq!(message_size(&msg).unwrap(), MAX_MESSAGE_SIZE);
+
+        let post = Content::Plain(Post::new(fits + "a"));
+        match publish(post, Some(&first), &pk, &sk, 1.0) {
+            Err(Error::MessageTooLarge { size }) => assert_eq!(size, MAX_MESSAGE_SIZE + 1),
+            other => panic!("expected the message to be too large, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_content_keys_are_rejected() {
+        use serde::ser::{Serialize, SerializeMap, Serializer};
+
+        // A `Value` object can't hold a key twice, so build the entries by hand.
+        struct Entries(Vec<(&'static str, Value)>);
+
+        impl Serialize for Entries {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(self.0.len()))?;
+                for (key, value) in &self.0 {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+
+        let (pk, sk) = generate_longterm_keypair();
+        let options = PublishOptions {
+            reject_duplicate_keys: true,
+            ..PublishOptions::default()
+        };
+        let content = |type_: &'static str| {
+            Content::Plain(Entries(vec![
+                ("type", Value::String("post".to_owned())),
+                ("text", Value::String("hi".to_owned())),
+                (type_, Value::String("vote".to_owned())),
+            ]))
+        };
+
+        match publish_with_options::<_, &[u8]>(content("type"), None, &pk, &sk, 0.0, &options) {
+            Err(Error::DuplicateContentKey { key }) => assert_eq!(key, "type"),
+            other => panic!("expected DuplicateContentKey, got {:?}", other),
+        }
+
+        let msg =
+            publish_with_options::<_, &[u8]>(content("channel"), None, &pk, &sk, 0.0, &options)
+                .unwrap();
+        assert!(verify_message(&msg).is_ok());
+    }
+
+    #[test]
+    fn lazy_content_is_not_built_when_publishing_fails_early() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (other_pk, other_sk) = generate_longterm_keypair();
+        let previous = publish::<_, &[u8]>(
+            Content::Plain(Post::new("one")),
+            None,
+            &other_pk,
+            &other_sk,
+            1.0,
+        )
+        .unwrap();
+
+        let mut built = false;
+        let result = publish_lazy(
+            || {
+                built = true;
+                Content::Plain(Post::new("two"))
+            },
+            Some(&previous),
+            &pk,
+            &sk,
+            2.0,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::PreviousMessageAuthorIsIncorrect {})
+        ));
+        assert!(!built);
+
+        let two = || Content::Plain(Post::new("two"));
+        let msg = publish_lazy(two, Some(&previous), &other_pk, &other_sk, 2.0).unwrap();
+        assert!(validate_message_hash_chain(&msg, Some(&previous)).is_ok());
+    }
+
+    #[test]
+    fn appending_checks_the_feed_length() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed = Vec::new();
+        for i in 0..3 {
+            let entry = publish_appending(
+                Content::Plain(Post::new("hi")),
+                &mut feed,
+                &pk,
+                &sk,
+                i as f64,
+            )
+            .unwrap();
+            assert_eq!(entry.sequence, i + 1);
+        }
+        assert_eq!(feed.len(), 3);
+        assert!(validate_feed(&feed).is_ok());
+
+        let mut gap = vec![feed[0].clone(), feed[2].clone()];
+        match publish_appending(Content::Plain(Post::new("hi")), &mut gap, &pk, &sk, 3.0) {
+            Err(Error::FeedLengthMismatch { length, sequence }) => {
+                assert_eq!((length, sequence), (2, 3))
+            }
+            other => panic!("expected FeedLengthMismatch, got {:?}", other),
+        }
+        assert_eq!(gap.len(), 2);
+    }
+
+    #[test]
+    fn control_chars_are_rejected_when_enabled() {
+        let (pk, sk) = generate_longterm_keypair();
+        let post = || Content::Plain(Post::new("nul \u{0000} here"));
+
+        let msg = publish::<_, &[u8]>(post(), None, &pk, &sk, 0.0).unwrap();
+        assert!(verify_message(&msg).is_ok());
+
+        let options = PublishOptions {
+            reject_control_chars: true,
+            ..PublishOptions::default()
+        };
+        match publish_with_options::<_, &[u8]>(post(), None, &pk, &sk, 0.0, &options) {
+            Err(Error::ControlCharInContent { character }) => assert_eq!(character, '\0'),
+            other => panic!("expected ControlCharInContent, got {:?}", other),
+        }
+
+        let post = Content::Plain(Post::new("two\nlines\tand a tab"));
+        assert!(publish_with_options::<_, &[u8]>(post, None, &pk, &sk, 0.0, &options).is_ok());
+    }
+
+    #[test]
+    fn random_content_reads_back_unchanged() {
+        use proptest::option;
+        use proptest::prelude::*;
+        use proptest::test_runner::TestRunner;
+        use serde::de::DeserializeOwned;
+        use serde::Serialize;
+        use ssb_multiformats::multihash::Target;
+        use std::fmt::Debug;
+
+        // Publish `content` as the first message of a feed, then read the message and its content
+        // back.
+        fn roundtrip<T>(content: &T, timestamp: f64) -> Result<(), TestCaseError>
+        where
+            T: Serialize + DeserializeOwned + PartialEq + Debug,
+        {
+            let (pk, sk) = generate_longterm_keypair();
+            let msg =
+                publish::<_, &[u8]>(Content::Plain(content), None, &pk, &sk, timestamp).unwrap();
+
+            let parsed = ParsedMessage::from_bytes(&msg).unwrap();
+            prop_assert_eq!(parsed.author, crate::author_of(&pk));
+            prop_assert_eq!(parsed.sequence, 1);
+            prop_assert_eq!(f64::from(parsed.timestamp), timestamp);
+            prop_assert_eq!(&content_as::<T>(&msg).unwrap(), content);
+            Ok(())
+        }
+
+        // Any characters at all, including control characters and quotes that must be escaped.
+        let text = || "(?s).{0,64}";
+        let feed = || any::<[u8; 32]>().prop_map(|key| Multikey::from_ed25519(&key));
+        let message =
+            any::<[u8; 32]>().prop_map(|hash| Multihash::from_sha256(hash, Target::Message));
+        let timestamp = || {
+            (0u64..1 << 40, 0u8..4).prop_map(|(ms, quarter)| ms as f64 + f64::from(quarter) / 4.0)
+        };
+
+        // Deterministic, so a failure shows up on every run.
+        let mut runner = TestRunner::deterministic();
+
+        let posts = (text(), option::of(text())).prop_map(|(text, channel)| Post {
+            channel,
+            ..Post::new(text)
+        });
+        runner
+            .run(&(posts, timestamp()), |(post, ts)| roundtrip(&post, ts))
+            .unwrap();
+
+        let contacts =
+            (feed(), any::<bool>(), any::<bool>()).prop_map(|(contact, following, blocking)| {
+                Contact {
+                    contact,
+                    following,
+                    blocking,
+                }
+            });
+        runner
+            .run(&(contacts, timestamp()), |(contact, ts)| {
+                roundtrip(&contact, ts)
+            })
+            .unwrap();
+
+        let votes = (message, -1i32..=1, text()).prop_map(|(link, value, expression)| Vote {
+            vote: VoteValue {
+                link,
+                value,
+                expression,
+            },
+        });
+        runner
+            .run(&(votes, timestamp()), |(vote, ts)| roundtrip(&vote, ts))
+            .unwrap();
+
+        let abouts = (
+            feed(),
+            option::of(text()),
+            option::of(text()),
+            option::of(text()),
+        )
+            .prop_map(|(about, name, description, image)| About {
+                about,
+                name,
+                description,
+                image,
+            });
+        runner
+            .run(&(abouts, timestamp()), |(about, ts)| roundtrip(&about, ts))
+            .unwrap();
+    }
+
+    #[test]
+    fn type_field_can_be_overridden() {
+        use ssb_legacy_msg_data::json::from_slice;
+        use ssb_legacy_msg_data::value::RidiculousStringMap;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let content_of = |msg: &[u8]| match from_slice::<SsbMessage>(msg).unwrap().value {
+            Value::Object(value) => match value.get("content") {
+                Some(Value::Object(content)) => content.clone(),
+                _ => panic!("content is not an object"),
+            },
+            _ => panic!("value is not an object"),
+        };
+
+        let options = PublishOptions {
+            strict: true,
+            ..PublishOptions::default()
+        };
+        let post = Content::Plain(Post::new("hi"));
+        let msg =
+            publish_with_options(post.clone(), None::<&[u8]>, &pk, &sk, 0.0, &options).unwrap();
+        let content = content_of(&msg);
+        assert_eq!(content.get("type"), Some(&Value::String("post".to_owned())));
+        assert_eq!(content.get("kind"), None);
+
+        let options = PublishOptions {
+            type_field: "kind".to_owned(),
+            ..options
+        };
+        let msg = publish_with_options(post, None::<&[u8]>, &pk, &sk, 0.0, &options).unwrap();
+        let content = content_of(&msg);
+        assert_eq!(content.get("kind"), Some(&Value::String("post".to_owned())));
+        assert_eq!(content.get("type"), None);
+        assert!(validate_envelope_with_type_field(&msg, "kind").is_ok());
+        assert!(validate_envelope(&msg).is_err());
+
+        let untyped = Content::Plain(Value::Object(RidiculousStringMap::with_capacity(0)));
+        match publish_with_options(untyped, None::<&[u8]>, &pk, &sk, 0.0, &options) {
+            Err(Error::InvalidEnvelope { reason }) => assert!(reason.contains("`kind`")),
+            other => panic!("expected an invalid envelope, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extracted_content_can_be_published_again() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (other, other_sk) = generate_longterm_keypair();
+        let post = Post::new("hi ☃").with_channel("rust");
+        let private = encrypt_to_groups(&post, &[vec![crate::author_of(&pk)]])
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let plain = publish(Content::Plain(post), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let encrypted = publish(private, Some(&plain), &pk, &sk, 1.0).unwrap();
+
+        for msg in &[plain, encrypted] {
+            let content = extract_content_bytes(msg).unwrap();
+            let republished =
+                publish_raw_content(&content, None::<&[u8]>, &other, &other_sk, 2.0).unwrap();
+            verify_message(&republished).unwrap();
+            assert_eq!(extract_content_bytes(&republished).unwrap(), content);
+        }
+
+        match publish_raw_content(br#""not a box""#, None::<&[u8]>, &pk, &sk, 2.0) {
+            Err(Error::ContentNotObject {}) => (),
+            other => panic!("expected ContentNotObject, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn self_references_are_rejected() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut post = Post::new("see below");
+        post.mentions.push(Mention {
+            link: SELF_REFERENCE.to_owned(),
+            name: None,
+            mime: None,
+            size: None,
+        });
+
+        match publish(Content::Plain(post), None::<&[u8]>, &pk, &sk, 0.0) {
+            Err(Error::SelfReferenceNotSupported {}) => (),
+            other => panic!("expected SelfReferenceNotSupported, got {:?}", other),
+        }
+
+        let mentioned = Post::new(format!("{} is only a placeholder in text", SELF_REFERENCE));
+        assert!(publish(Content::Plain(mentioned), None::<&[u8]>, &pk, &sk, 0.0).is_ok());
+    }
+
+    #[test]
+    fn republishing_extracted_content_is_idempotent() {
+        let (pk, sk) = generate_longterm_keypair();
+        let tricky: Value = from_slice(
+            r#"{"type": "test", "b": [1.5, 100, null], "2": "é\"😀\u0001", "1": {"z": true}}"#
+                .as_bytes(),
+        )
+        .unwrap();
+        let private = encrypt_to_groups(&Post::new("secret"), &[vec![crate::author_of(&pk)]])
+            .unwrap()
+            .pop()
+            .unwrap();
+
+        let first = publish(
+            Content::Plain(Post::new("first")),
+            None::<&[u8]>,
+            &pk,
+            &sk,
+            0.0,
+        )
+        .unwrap();
+        let plain = publish(Content::Plain(tricky), Some(&first), &pk, &sk, 1.5).unwrap();
+        let encrypted = publish(private, Some(&first), &pk, &sk, 1.5).unwrap();
+
+        for msg in &[plain, encrypted] {
+            let content = extract_content_bytes(msg).unwrap();
+            let republished = publish_raw_content(&content, Some(&first), &pk, &sk, 1.5).unwrap();
+            assert_eq!(&republished, msg);
+            assert_eq!(
+                ParsedMessage::from_bytes(&republished).unwrap().key,
+                ParsedMessage::from_bytes(msg).unwrap().key
+            );
+        }
     }
 }
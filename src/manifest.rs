@@ -0,0 +1,149 @@
+//! Listing the messages of a published batch, for checking that all of them arrived.
+
+use serde::{Deserialize, Serialize};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multihash::Multihash;
+
+use crate::{
+    content_type, legacy_timestamp, parse_previous, publish_parsed, Content, ParsedMessage,
+    PublishOptions, Result,
+};
+
+/// A list of the messages of a batch, returned by [publish_batch_with_manifest].
+///
+/// Send it along with the messages: the receiver can check with [Manifest::matches] that it got
+/// exactly the messages that were published.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// The messages of the batch, in order.
+    pub entries: Vec<ManifestEntry>,
+    /// The key of the latest message of the feed after the batch, or `None` if the feed is still
+    /// empty.
+    pub tip: Option<Multihash>,
+}
+
+/// A message listed in a [Manifest].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    /// The position of the message in its feed, starting at 1.
+    pub sequence: u64,
+    /// The message key.
+    pub key: Multihash,
+    /// The claimed publishing time, in milliseconds since the unix epoch.
+    pub timestamp: f64,
+    /// The `type` of the content, or `None` if the content is encrypted.
+    pub content_type: Option<String>,
+}
+
+impl Manifest {
+    /// Whether `messages` are exactly the `{key, value}` messages listed, in order.
+    ///
+    /// Only the listed fields are compared. Validate the messages as well, eg. with
+    /// [validate_feed](crate::validate_feed), before trusting them.
+    pub fn matches<I>(&self, messages: I) -> Result<bool>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut entries = self.entries.iter();
+        for message in messages {
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+            let parsed = ParsedMessage::from_bytes(message.as_ref())?;
+            if parsed.sequence != entry.sequence
+                || parsed.key != entry.key
+                || f64::from(parsed.timestamp) != entry.timestamp
+                || content_type(message.as_ref())? != entry.content_type
+            {
+                return Ok(false);
+            }
+        }
+        Ok(entries.next().is_none())
+    }
+}
+
+/// Publish `contents` one after the other, like [publish](crate::publish), each with its
+/// timestamp, also returning a [Manifest] of the new messages.
+pub fn publish_batch_with_manifest<T, P, I>(
+    contents: I,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+) -> Result<(Vec<Vec<u8>>, Manifest)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+    I: IntoIterator<Item = (Content<T>, f64)>,
+{
+    let mut previous = parse_previous(previous_msg_value_bytes)?;
+
+    let mut messages = Vec::new();
+    let mut entries = Vec::new();
+    for (content, timestamp) in contents {
+        let (message, entry) = publish_parsed(
+            content,
+            previous.as_ref(),
+            public_key,
+            secret_key,
+            legacy_timestamp(timestamp)?,
+            &PublishOptions::default(),
+        )?;
+
+        previous = Some(ParsedMessage::from(entry.clone()));
+        entries.push(ManifestEntry {
+            sequence: entry.sequence,
+            key: entry.key,
+            timestamp: f64::from(entry.timestamp),
+            content_type: entry.content_type,
+        });
+        messages.push(message);
+    }
+
+    let manifest = Manifest {
+        entries,
+        tip: previous.map(|previous| previous.key),
+    };
+    Ok((messages, manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        publish, publish_batch_with_manifest, validate_feed, Content, Manifest, ParsedMessage, Post,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn manifest_lists_the_published_batch() {
+        let (pk, sk) = generate_longterm_keypair();
+        let first = publish(Content::Plain(Post::new("0")), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+
+        let contents = (1..4).map(|i| (Content::Plain(Post::new(format!("{}", i))), i as f64));
+        let (messages, manifest) =
+            publish_batch_with_manifest(contents, Some(&first), &pk, &sk).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert!(validate_feed(std::iter::once(&first).chain(&messages)).is_ok());
+
+        for (message, entry) in messages.iter().zip(&manifest.entries) {
+            let parsed = ParsedMessage::from_bytes(message).unwrap();
+            assert_eq!(entry.sequence, parsed.sequence);
+            assert_eq!(entry.key, parsed.key);
+            assert_eq!(entry.timestamp, f64::from(parsed.timestamp));
+            assert_eq!(entry.content_type.as_deref(), Some("post"));
+        }
+        let tip = ParsedMessage::from_bytes(messages.last().unwrap())
+            .unwrap()
+            .key;
+        assert_eq!(manifest.tip, Some(tip));
+
+        let sent = serde_json::to_string(&manifest).unwrap();
+        let received: Manifest = serde_json::from_str(&sent).unwrap();
+        assert!(received.matches(&messages).unwrap());
+        assert!(!received.matches(&messages[..2]).unwrap());
+        assert!(!received
+            .matches(&[&messages[0], &messages[2], &messages[1]])
+            .unwrap());
+    }
+}
@@ -0,0 +1,146 @@
+//! Moving a whole feed to a new key.
+
+use snafu::ResultExt;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_legacy_msg_data::value::Value;
+use ssb_multiformats::multibox::Multibox;
+
+use crate::validate;
+use crate::{
+    publish_parsed, validate_envelope, Content, Error, InvalidMessage, ParsedMessage,
+    PublishOptions, Result, SsbMessage,
+};
+
+/// Re-sign every message of a feed under a new key, writing the new feed to `writer`.
+///
+/// `messages` must yield the `{key, value}` messages of the old feed in order. Each message's
+/// content and timestamp are kept, while the `author`, `previous` links and signatures are
+/// rebuilt for the new key. Every new message is validated against the one before it as it is
+/// written, and migration stops at the first failure.
+pub fn migrate_feed<I, W>(
+    messages: I,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    writer: &mut W,
+) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+    W: Extend<Vec<u8>>,
+{
+    // The old feed's timestamps are kept as they are, however far ahead of the clock they are.
+    let options = PublishOptions {
+        max_future_skew: None,
+        ..PublishOptions::default()
+    };
+    let mut previous: Option<(Vec<u8>, ParsedMessage)> = None;
+
+    for message in messages {
+        let message = message.as_ref();
+        validate_envelope(message)?;
+
+        let message = from_slice::<SsbMessage>(message).context(InvalidMessage)?;
+        let mut value = match message.value {
+            Value::Object(value) => value,
+            _ => return Err(invalid("`value` must be an object")),
+        };
+        let timestamp = match value.remove("timestamp".to_owned()) {
+            Some(Value::Float(timestamp)) => timestamp,
+            _ => return Err(invalid("`timestamp` must be a number")),
+        };
+        let content = match value.remove("content".to_owned()) {
+            Some(Value::String(boxed)) => Content::Encrypted(
+                Multibox::from_legacy(boxed.as_bytes())
+                    .map_err(|_| invalid("encrypted `content` is not a valid box"))?
+                    .0,
+            ),
+            Some(content) => Content::Plain(content),
+            None => return Err(invalid("`content` is missing")),
+        };
+
+        let (migrated, entry) = publish_parsed(
+            content,
+            previous.as_ref().map(|(_, parsed)| parsed),
+            public_key,
+            secret_key,
+            timestamp,
+            &options,
+        )?;
+
+        validate::check_link(
+            &migrated,
+            previous.as_ref().map(|(bytes, _)| bytes.as_slice()),
+        )?;
+        validate::check_signature(&migrated)?;
+
+        writer.extend(Some(migrated.clone()));
+        previous = Some((migrated, ParsedMessage::from(entry)));
+    }
+
+    Ok(())
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidEnvelope {
+        reason: reason.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::example_contact;
+    use crate::{migrate_feed, publish, validate_feed, Content, ParsedMessage};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_multiformats::multikey::Multikey;
+    use std::convert::TryInto;
+
+    #[test]
+    fn migrate_feed_to_a_new_key() {
+        let (old_pk, old_sk) = generate_longterm_keypair();
+        let (new_pk, new_sk) = generate_longterm_keypair();
+
+        let msg1 = publish::<_, &[u8]>(
+            Content::Plain(example_contact(true)),
+            None,
+            &old_pk,
+            &old_sk,
+            1.0,
+        )
+        .unwrap();
+        let msg2 = publish(
+            Content::Plain(example_contact(false)),
+            Some(&msg1),
+            &old_pk,
+            &old_sk,
+            2.0,
+        )
+        .unwrap();
+        let msg3 = publish(
+            Content::Plain(example_contact(true)),
+            Some(&msg2),
+            &old_pk,
+            &old_sk,
+            3.0,
+        )
+        .unwrap();
+        let old_feed = vec![msg1, msg2, msg3];
+
+        let mut new_feed: Vec<Vec<u8>> = Vec::new();
+        migrate_feed(&old_feed, &new_pk, &new_sk, &mut new_feed).unwrap();
+
+        assert_eq!(new_feed.len(), 3);
+        assert!(validate_feed(&new_feed).is_ok());
+
+        let new_author = Multikey::from_ed25519(new_pk.as_ref().try_into().unwrap());
+        for (old, new) in old_feed.iter().zip(new_feed.iter()) {
+            let old_parsed = ParsedMessage::from_bytes(old).unwrap();
+            let new_parsed = ParsedMessage::from_bytes(new).unwrap();
+
+            assert_eq!(new_parsed.author, new_author);
+            assert_eq!(new_parsed.sequence, old_parsed.sequence);
+            assert_eq!(new_parsed.timestamp, old_parsed.timestamp);
+            assert_ne!(new_parsed.key, old_parsed.key);
+        }
+    }
+}
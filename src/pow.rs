@@ -0,0 +1,255 @@
+//! Proof of work, for networks that only accept messages that were expensive to publish.
+
+use serde::Serialize;
+use snafu::{ensure, ResultExt};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_legacy_msg_data::LegacyF64;
+use ssb_multiformats::multihash::Multihash;
+
+use crate::canonical::{self, CanonicalJson};
+use crate::hasher::{self, MessageHasher};
+use crate::stages::{self, build_message, encode_signable, sign};
+use crate::{
+    check_before_content, checked_content, legacy_length, legacy_timestamp, parse_previous,
+    Content, ContentNotObject, DifficultyNotReached, DifficultyTooHigh, InvalidEnvelope,
+    InvalidMessage, MessageTooLarge, ParsedMessage, PublishOptions, Result, SsbMessage,
+    MAX_MESSAGE_SIZE,
+};
+
+// The largest nonce, the largest integer that is exact as a legacy float.
+const MAX_NONCE: u64 = (1 << 53) - 1;
+
+/// The highest difficulty [publish_with_pow] searches for.
+///
+/// There are 2^53 nonces, and a key meets a difficulty of 48 bits once every 2^48 tries on
+/// average, so the search all but surely succeeds. It would still take years.
+pub const MAX_DIFFICULTY: u32 = 48;
+
+/// Publish a new message, like [publish_with_options](crate::publish_with_options), whose key
+/// starts with at least `difficulty` zero bits.
+///
+/// Nonces are tried in a `nonce` entry of the message value, which is signed along with the rest
+/// of it, until the key meets the difficulty. Every extra bit of difficulty doubles the expected
+/// number of tries, and each try signs and hashes the message once. A `difficulty` above
+/// [MAX_DIFFICULTY] fails with [Error::DifficultyTooHigh](crate::Error::DifficultyTooHigh), and if
+/// no nonce meets the difficulty publishing fails with
+/// [Error::DifficultyNotReached](crate::Error::DifficultyNotReached).
+///
+/// These messages are not valid classic messages: the JS `isValidOrder` check and
+/// [validate_envelope](crate::validate_envelope) always reject the `nonce` entry. Only publish them
+/// to networks that ask for proof of work. For the same reason, [strict](PublishOptions::strict)
+/// options fail with [Error::InvalidEnvelope](crate::Error::InvalidEnvelope) before any nonce is
+/// tried.
+pub fn publish_with_pow<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+    difficulty: u32,
+    options: &PublishOptions,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    ensure!(
+        difficulty <= MAX_DIFFICULTY,
+        DifficultyTooHigh {
+            difficulty,
+            max: MAX_DIFFICULTY
+        }
+    );
+    ensure!(
+        !options.strict,
+        InvalidEnvelope {
+            reason: "the `nonce` entry of proof of work messages is never valid"
+        }
+    );
+
+    let previous = parse_previous(previous_msg_value_bytes)?;
+    let timestamp = legacy_timestamp(timestamp)?;
+    check_before_content(previous.as_ref(), public_key, timestamp, options)?;
+
+    let content = checked_content(content, options)?;
+    let value = unsigned_value(content, previous.as_ref(), public_key, timestamp)?;
+
+    // Signatures all have the same length, so no nonce makes the message longer than the largest.
+    let (_, widest_bytes) = sign_value(with_nonce(&value, MAX_NONCE), secret_key)?;
+    let size = legacy_length(&widest_bytes);
+    ensure!(size <= MAX_MESSAGE_SIZE, MessageTooLarge { size });
+
+    for nonce in 0..=MAX_NONCE {
+        let (signed, published_bytes) = sign_value(with_nonce(&value, nonce), secret_key)?;
+        let key = hasher::DEFAULT.hash(&published_bytes);
+        if key_difficulty(&key) < difficulty {
+            continue;
+        }
+
+        return stages::wrap(&key, signed);
+    }
+
+    DifficultyNotReached { difficulty }.fail()
+}
+
+// The message `value` with a `nonce` entry.
+fn with_nonce(value: &RidiculousStringMap<Value>, nonce: u64) -> RidiculousStringMap<Value> {
+    let mut value = value.clone();
+    // Nonces up to `MAX_NONCE` are exact as legacy floats.
+    value.insert(
+        "nonce".to_owned(),
+        Value::Float(LegacyF64::from_f64(nonce as f64).unwrap()),
+    );
+    value
+}
+
+// The unsigned value of the message publishing `content`, for adding entries to it before it is
+// signed with `sign_value`.
+fn unsigned_value<T: Serialize>(
+    content: Content<T>,
+    previous: Option<&ParsedMessage>,
+    public_key: &PublicKey,
+    timestamp: LegacyF64,
+) -> Result<RidiculousStringMap<Value>> {
+    let is_plain = match &content {
+        Content::Plain(_) => true,
+        Content::Encrypted(_) => false,
+    };
+    let message = build_message(content, previous, public_key, timestamp);
+
+    let value = match canonical::DEFAULT.decode_value(&encode_signable(&message)?)? {
+        Value::Object(value) => value,
+        _ => return ContentNotObject.fail(),
+    };
+    ensure!(
+        !is_plain || matches!(value.get("content"), Some(Value::Object(_))),
+        ContentNotObject
+    );
+    Ok(value)
+}
+
+// Sign an unsigned message value, returning the signed value and its encoding.
+fn sign_value(
+    mut value: RidiculousStringMap<Value>,
+    secret_key: &SecretKey,
+) -> Result<(Value, Vec<u8>)> {
+    let json = canonical::DEFAULT;
+
+    let signable_bytes = json.encode_value(&Value::Object(value.clone()))?;
+    let signature = sign(&signable_bytes, secret_key);
+    value.insert(
+        "signature".to_owned(),
+        Value::String(signature.to_legacy_string()),
+    );
+
+    let value = Value::Object(value);
+    let value_bytes = json.encode_value(&value)?;
+    Ok((value, value_bytes))
+}
+
+/// The number of leading zero bits of the key of a `{key, value}` message, the difficulty its
+/// proof of work meets.
+///
+/// The key itself is not checked against the value.
+pub fn pow_difficulty(msg_bytes: &[u8]) -> Result<u32> {
+    let message = from_slice::<SsbMessage>(msg_bytes).context(InvalidMessage)?;
+    Ok(key_difficulty(&message.key))
+}
+
+fn key_difficulty(key: &Multihash) -> u32 {
+    let legacy = key.to_legacy_string();
+    let encoded = legacy.trim_start_matches('%').trim_end_matches(".sha256");
+    // A parsed key is always valid base64.
+    leading_zero_bits(&base64::decode(encoded).unwrap())
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        validate_envelope, verify_keys_match, Content, Error, Post, PublishOptions,
+        MAX_MESSAGE_SIZE,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_validate::validate_message_hash_chain;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn pow_messages_meet_their_difficulty() {
+        use crate::{pow_difficulty, publish_with_pow};
+
+        let (pk, sk) = generate_longterm_keypair();
+        let options = PublishOptions::default();
+        let post = |text| Content::Plain(Post::new(text));
+        let first =
+            publish_with_pow::<_, &[u8]>(post("one"), None, &pk, &sk, 1.0, 8, &options).unwrap();
+        let second =
+            publish_with_pow(post("two"), Some(&first), &pk, &sk, 2.0, 8, &options).unwrap();
+
+        for msg in &[&first, &second] {
+            assert!(pow_difficulty(msg).unwrap() >= 8);
+            assert!(verify_keys_match(&[msg]).is_ok());
+            assert!(verify_message(msg).is_ok());
+            assert!(validate_envelope(msg).is_err());
+        }
+        assert!(validate_message_hash_chain(&second, Some(&first)).is_ok());
+    }
+
+    #[test]
+    fn unpublishable_messages_fail_before_the_search() {
+        use crate::pow::MAX_DIFFICULTY;
+        use crate::publish_with_pow;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let options = PublishOptions::default();
+        let post = |text: &str| Content::Plain(Post::new(text));
+
+        // None of these could ever be published, so searching for a nonce would never end.
+        let too_high = MAX_DIFFICULTY + 1;
+        match publish_with_pow::<_, &[u8]>(post("hi"), None, &pk, &sk, 0.0, too_high, &options) {
+            Err(Error::DifficultyTooHigh { difficulty, max }) => {
+                assert_eq!((difficulty, max), (too_high, MAX_DIFFICULTY))
+            }
+            other => panic!("expected DifficultyTooHigh, got {:?}", other),
+        }
+        let strict = PublishOptions {
+            strict: true,
+            ..PublishOptions::default()
+        };
+        match publish_with_pow::<_, &[u8]>(post("hi"), None, &pk, &sk, 0.0, MAX_DIFFICULTY, &strict)
+        {
+            Err(Error::InvalidEnvelope { .. }) => (),
+            other => panic!("expected InvalidEnvelope, got {:?}", other),
+        }
+        let long = "a".repeat(MAX_MESSAGE_SIZE);
+        match publish_with_pow::<_, &[u8]>(
+            post(&long),
+            None,
+            &pk,
+            &sk,
+            0.0,
+            MAX_DIFFICULTY,
+            &options,
+        ) {
+            Err(Error::MessageTooLarge { .. }) => (),
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+        let text = Content::Plain("hi");
+        match publish_with_pow::<_, &[u8]>(text, None, &pk, &sk, 0.0, MAX_DIFFICULTY, &options) {
+            Err(Error::ContentNotObject {}) => (),
+            other => panic!("expected ContentNotObject, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Showing the key of a message before handing it over.
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multihash::Multihash;
+
+use crate::{legacy_timestamp, parse_previous, publish_parsed, Content, PublishOptions, Result};
+
+/// A published message whose key has been looked at, but whose bytes have not been taken yet.
+/// See [preview_then_finish].
+#[derive(Debug, Clone)]
+pub struct Preview {
+    key: Multihash,
+    message: Vec<u8>,
+}
+
+impl Preview {
+    /// The key of the message.
+    pub fn key(&self) -> &Multihash {
+        &self.key
+    }
+
+    /// The `{key, value}` message, as [publish](crate::publish) returns it.
+    pub fn finish(self) -> Vec<u8> {
+        self.message
+    }
+}
+
+/// Publish a new message, like [publish](crate::publish), in two steps: first look at its key,
+/// then [finish](Preview::finish) to take the message.
+///
+/// This is for UIs that show a new message optimistically, with its key, while it is still being
+/// stored. The key depends on the whole signed message, so all of the work is done before the
+/// key is available, and finishing only hands over the bytes. Splitting the steps just lets the
+/// caller act on the key first.
+pub fn preview_then_finish<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Preview>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    let (message, entry) = publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+    )?;
+    Ok(Preview {
+        key: entry.key,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{preview_then_finish, publish, Content, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn previewed_key_matches_the_finished_message() {
+        let (pk, sk) = generate_longterm_keypair();
+        let post = || Content::Plain(Post::new("soon"));
+
+        let preview = preview_then_finish(post(), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let key = preview.key().clone();
+        let msg = preview.finish();
+
+        assert_eq!(ParsedMessage::from_bytes(&msg).unwrap().key, key);
+        assert_eq!(msg, publish(post(), None::<&[u8]>, &pk, &sk, 0.0).unwrap());
+        verify_message(&msg).unwrap();
+    }
+}
@@ -0,0 +1,344 @@
+//! Encrypting parts of content to recipients with
+//! [private-box](https://ssbc.github.io/scuttlebutt-protocol-guide/#private-messages).
+
+use serde::Serialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::json::{from_slice, to_vec};
+use ssb_legacy_msg_data::value::Value;
+use ssb_multiformats::multibox::Multibox;
+use ssb_multiformats::multikey::Multikey;
+
+use crate::content;
+use crate::{
+    publish_with_options, stages, Content, ContentNotEncrypted, ContentNotObject, DecryptionFailed,
+    FieldNotFound, InvalidFeedId, InvalidMessage, InvalidRecipients, LegacyJsonEncodeFailed,
+    PublishOptions, Result, SsbMessage,
+};
+
+/// The maximum number of recipients of a private message.
+pub const MAX_RECIPIENTS: usize = 7;
+
+/// Encrypt the entries named in `fields` of the `content` object to `recipients`, leaving the
+/// other entries public.
+///
+/// Each encrypted entry is replaced with a string holding the private-boxed json of its value,
+/// in the same `<base64>.box` form used for encrypted message content. The returned object can be
+/// published with `Content::Plain`. Use [decrypt_field] to read an entry back.
+pub fn encrypt_fields<T: Serialize>(
+    content: &T,
+    fields: &[&str],
+    recipients: &[PublicKey],
+) -> Result<Value> {
+    ensure!(
+        !recipients.is_empty() && recipients.len() <= MAX_RECIPIENTS,
+        InvalidRecipients {
+            count: recipients.len()
+        }
+    );
+
+    let mut content = content::to_value(content)?;
+    let map = match content {
+        Value::Object(ref mut map) => map,
+        _ => return ContentNotObject.fail(),
+    };
+
+    for field in fields {
+        let value = map
+            .get_mut(field)
+            .context(FieldNotFound { field: *field })?;
+        let plaintext = to_vec(value, true)
+            .map_err(|_| snafu::NoneError)
+            .context(LegacyJsonEncodeFailed)?;
+        *value = Value::String(encrypt_bytes(&plaintext, recipients));
+    }
+
+    Ok(content)
+}
+
+/// Encrypt `content` separately to each group of recipients in `groups`.
+///
+/// Returns one `Content::Encrypted` per group, in the same order, each readable only by the
+/// members of its group. Fails without encrypting anything if any group is empty or has more
+/// than [MAX_RECIPIENTS] members.
+pub fn encrypt_to_groups<T: Serialize>(
+    content: &T,
+    groups: &[Vec<Multikey>],
+) -> Result<Vec<Content<T>>> {
+    for group in groups {
+        ensure!(
+            !group.is_empty() && group.len() <= MAX_RECIPIENTS,
+            InvalidRecipients { count: group.len() }
+        );
+    }
+
+    let content = content::to_value(content)?;
+    ensure!(matches!(content, Value::Object(_)), ContentNotObject);
+    let plaintext = to_vec(&content, true)
+        .map_err(|_| snafu::NoneError)
+        .context(LegacyJsonEncodeFailed)?;
+
+    groups
+        .iter()
+        .map(|group| {
+            let recipients = group
+                .iter()
+                .map(|key| {
+                    key.clone()
+                        .into_ed25519_public_key()
+                        .context(InvalidFeedId {
+                            id: key.to_legacy_string(),
+                        })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Content::Encrypted(private_box(&plaintext, &recipients)))
+        })
+        .collect()
+}
+
+/// Encrypt `content` to `recipients` and publish it, like [publish_with_options].
+///
+/// With `recps_hint` the `{key, value}` message also lists the recipients' ids in a `recps` entry
+/// next to `key`. It is outside the signed value, so the message stays valid, but **it makes
+/// public who a private message is for**, which the encryption otherwise hides. Only turn it on
+/// for clients that index private messages by recipient and accept that, and strip the entry
+/// before replicating the message to peers that shouldn't see it.
+#[allow(clippy::too_many_arguments)]
+pub fn publish_private<T, P>(
+    content: &T,
+    recipients: &[Multikey],
+    recps_hint: bool,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+    options: &PublishOptions,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let mut encrypted = encrypt_to_groups(content, &[recipients.to_vec()])?;
+    let message = publish_with_options(
+        encrypted.pop().unwrap(),
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+        options,
+    )?;
+    if !recps_hint {
+        return Ok(message);
+    }
+
+    let message = from_slice::<SsbMessage>(&message).context(InvalidMessage)?;
+    stages::wrap_with_recps(&message.key, message.value, recipients)
+}
+
+/// Decrypt the entry `field` of `content`, which was encrypted with [encrypt_fields].
+///
+/// Returns `None` if the entry was not encrypted to `secret_key`.
+pub fn decrypt_field(
+    content: &Value,
+    field: &str,
+    secret_key: &SecretKey,
+) -> Result<Option<Value>> {
+    let boxed = match content {
+        Value::Object(map) => map.get(field).context(FieldNotFound { field })?,
+        _ => return ContentNotObject.fail(),
+    };
+    let boxed = match boxed {
+        Value::String(boxed) => boxed,
+        _ => return ContentNotEncrypted.fail(),
+    };
+
+    match decrypt_bytes(boxed, secret_key)? {
+        Some(plaintext) => from_slice(&plaintext)
+            .map(Some)
+            .map_err(|_| snafu::NoneError)
+            .context(DecryptionFailed),
+        None => Ok(None),
+    }
+}
+
+// Private-box `plaintext` to `recipients`, in the legacy `<base64>.box` encoding.
+pub(crate) fn encrypt_bytes(plaintext: &[u8], recipients: &[PublicKey]) -> String {
+    private_box(plaintext, recipients).to_legacy_string()
+}
+
+fn private_box(plaintext: &[u8], recipients: &[PublicKey]) -> Multibox {
+    private_box::init();
+    Multibox::new_private_box(private_box::encrypt(plaintext, recipients))
+}
+
+// Open a legacy `<base64>.box` string, returning `None` if it is not for `secret_key`.
+pub(crate) fn decrypt_bytes(boxed: &str, secret_key: &SecretKey) -> Result<Option<Vec<u8>>> {
+    ensure!(
+        Multibox::from_legacy(boxed.as_bytes()).is_ok(),
+        ContentNotEncrypted
+    );
+    let cyphertext = base64::decode(boxed.trim_end_matches(".box"))
+        .map_err(|_| snafu::NoneError)
+        .context(ContentNotEncrypted)?;
+    ensure!(cyphertext.len() > 56, DecryptionFailed);
+
+    private_box::init();
+    Ok(private_box::decrypt(&cyphertext, secret_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::example_contact;
+    use crate::{
+        decrypt_field, encrypt_fields, encrypt_to_groups, publish, publish_private, Contact,
+        Content, Error, Post, PublishOptions, MAX_RECIPIENTS,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::json::from_slice;
+    use ssb_legacy_msg_data::value::Value;
+    use ssb_validate::validate_message_hash_chain;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn encrypted_fields_stay_private() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (friend_pk, friend_sk) = generate_longterm_keypair();
+
+        let contact = Contact {
+            blocking: true,
+            ..example_contact(true)
+        };
+        let content = encrypt_fields(&contact, &["blocking"], &[friend_pk]).unwrap();
+        let msg = publish::<_, &[u8]>(Content::Plain(content), None, &pk, &sk, 0.0).unwrap();
+
+        assert!(validate_message_hash_chain::<_, &[u8]>(&msg, None).is_ok());
+        assert!(verify_message(&msg).is_ok());
+
+        let content = match from_slice::<Value>(&msg).unwrap() {
+            Value::Object(msg) => match msg.get("value") {
+                Some(Value::Object(value)) => value.get("content").unwrap().clone(),
+                _ => panic!("message has no value"),
+            },
+            _ => panic!("message is not an object"),
+        };
+        match content {
+            Value::Object(ref map) => {
+                assert_eq!(map.get("following"), Some(&Value::Bool(true)));
+                match map.get("blocking") {
+                    Some(Value::String(boxed)) => assert!(boxed.ends_with(".box")),
+                    other => panic!("expected an encrypted field, got {:?}", other),
+                }
+            }
+            _ => panic!("content is not an object"),
+        }
+
+        assert_eq!(
+            decrypt_field(&content, "blocking", &friend_sk).unwrap(),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(decrypt_field(&content, "blocking", &sk).unwrap(), None);
+    }
+
+    #[test]
+    fn groups_are_encrypted_separately() {
+        use crate::private::decrypt_bytes;
+        use ssb_legacy_msg_data::json::to_vec;
+
+        let (alice, alice_sk) = generate_longterm_keypair();
+        let (bob, bob_sk) = generate_longterm_keypair();
+        let (carol, carol_sk) = generate_longterm_keypair();
+        let key = crate::author_of;
+
+        let post = Post::new("secret");
+        let groups = vec![vec![key(&alice), key(&bob)], vec![key(&carol)]];
+        let encrypted = encrypt_to_groups(&post, &groups).unwrap();
+        assert_eq!(encrypted.len(), 2);
+
+        let boxed: Vec<String> = encrypted
+            .iter()
+            .map(|content| match content {
+                Content::Encrypted(boxed) => boxed.to_legacy_string(),
+                Content::Plain(_) => panic!("content was not encrypted"),
+            })
+            .collect();
+        let expected = to_vec(&post, true).unwrap();
+        for (boxed, readers, others) in &[
+            (&boxed[0], vec![&alice_sk, &bob_sk], vec![&carol_sk]),
+            (&boxed[1], vec![&carol_sk], vec![&alice_sk, &bob_sk]),
+        ] {
+            for sk in readers {
+                assert_eq!(decrypt_bytes(boxed, sk).unwrap().unwrap(), expected);
+            }
+            for sk in others {
+                assert_eq!(decrypt_bytes(boxed, sk).unwrap(), None);
+            }
+        }
+
+        let too_many = vec![vec![key(&alice)], vec![key(&bob); MAX_RECIPIENTS + 1]];
+        match encrypt_to_groups(&post, &too_many) {
+            Err(Error::InvalidRecipients { count }) => assert_eq!(count, MAX_RECIPIENTS + 1),
+            other => panic!(
+                "expected InvalidRecipients, got {:?}",
+                other.map(|c| c.len())
+            ),
+        }
+    }
+
+    #[test]
+    fn recipients_are_only_hinted_when_asked() {
+        use crate::private::decrypt_bytes;
+        use crate::validate_envelope;
+        use ssb_legacy_msg_data::json::{from_slice, to_vec};
+
+        let (pk, sk) = generate_longterm_keypair();
+        let (bob, bob_sk) = generate_longterm_keypair();
+        let recipients = vec![crate::author_of(&pk), crate::author_of(&bob)];
+        let post = Post::new("secret");
+
+        for &recps_hint in &[false, true] {
+            let msg = publish_private(
+                &post,
+                &recipients,
+                recps_hint,
+                None::<&[u8]>,
+                &pk,
+                &sk,
+                0.0,
+                &PublishOptions::default(),
+            )
+            .unwrap();
+            // The hint is not part of the signed value, so the message is as valid as any other.
+            verify_message(&msg).unwrap();
+            validate_message_hash_chain::<_, &[u8]>(&msg, None).unwrap();
+            validate_envelope(&msg).unwrap();
+
+            let msg = match from_slice::<Value>(&msg).unwrap() {
+                Value::Object(msg) => msg,
+                _ => panic!("message is not an object"),
+            };
+            let value = match msg.get("value") {
+                Some(Value::Object(value)) => value,
+                _ => panic!("message has no value"),
+            };
+            assert_eq!(value.get("recps"), None);
+            match (recps_hint, msg.get("recps")) {
+                (false, None) => {}
+                (true, Some(Value::Array(recps))) => {
+                    let ids: Vec<Value> = recipients
+                        .iter()
+                        .map(|id| Value::String(id.to_legacy_string()))
+                        .collect();
+                    assert_eq!(recps, &ids);
+                }
+                (_, recps) => panic!("unexpected recps {:?}", recps),
+            }
+
+            let boxed = match value.get("content") {
+                Some(Value::String(boxed)) => boxed,
+                _ => panic!("content was not encrypted"),
+            };
+            let expected = to_vec(&post, true).unwrap();
+            assert_eq!(decrypt_bytes(boxed, &bob_sk).unwrap().unwrap(), expected);
+        }
+    }
+}
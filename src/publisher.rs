@@ -0,0 +1,163 @@
+//! A single entry point for apps that publish to one feed.
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{
+    author_of, About, Contact, Content, Feed, ParsedMessage, Post, Result, TimestampPolicy, Vote,
+    VoteValue,
+};
+
+/// A feed together with everything needed to publish to it: its keys, where timestamps come from
+/// and its latest message.
+///
+/// Each method publishes one message after the previous one and returns it as a `{key, value}`
+/// message, ready to be stored and replicated.
+///
+/// ```
+/// use ssb_crypto::generate_longterm_keypair;
+/// use ssb_multiformats::multikey::Multikey;
+/// use ssb_publish::Publisher;
+///
+/// let (pk, sk) = generate_longterm_keypair();
+/// let mut publisher = Publisher::new(pk, sk);
+///
+/// publisher.about(Some("alice"), None, None).unwrap();
+/// publisher.post("hello world").unwrap();
+///
+/// let (friend, _) =
+///     Multikey::from_legacy(b"@9Zf0se86PotjNqaOt9ue8BNBLkGVLQcLNDw/pRQHY3U=.ed25519").unwrap();
+/// publisher.contact(friend, true, false).unwrap();
+///
+/// assert_eq!(publisher.latest().unwrap().sequence, 3);
+/// ```
+#[derive(Debug)]
+pub struct Publisher {
+    feed: Feed,
+    timestamps: TimestampPolicy,
+}
+
+impl Publisher {
+    /// A publisher of a new feed, timestamping messages with the system clock.
+    pub fn new(public_key: PublicKey, secret_key: SecretKey) -> Publisher {
+        Publisher::from_feed(Feed::new(public_key, secret_key))
+    }
+
+    /// A publisher continuing `feed`, eg. one restored with [Feed::restore_state] and configured
+    /// with [Feed::with_options].
+    pub fn from_feed(feed: Feed) -> Publisher {
+        Publisher {
+            feed,
+            timestamps: TimestampPolicy::Clock,
+        }
+    }
+
+    /// Choose timestamps with `timestamps` instead of the system clock.
+    pub fn with_timestamps(mut self, timestamps: TimestampPolicy) -> Publisher {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// The feed being published to.
+    pub fn feed(&self) -> &Feed {
+        &self.feed
+    }
+
+    /// The latest message of the feed, or `None` if nothing has been published.
+    pub fn latest(&self) -> Option<&ParsedMessage> {
+        self.feed.latest()
+    }
+
+    /// Publish any content.
+    pub fn publish<T: Serialize>(&mut self, content: Content<T>) -> Result<Vec<u8>> {
+        self.feed.publish_with_policy(content, &mut self.timestamps)
+    }
+
+    /// Publish a public [Post] of `text`.
+    pub fn post(&mut self, text: &str) -> Result<Vec<u8>> {
+        self.publish(Content::Plain(Post::new(text)))
+    }
+
+    /// Publish a [Contact] following, unfollowing or blocking the feed `feed`.
+    pub fn contact(&mut self, feed: Multikey, following: bool, blocking: bool) -> Result<Vec<u8>> {
+        let contact = Contact {
+            contact: feed,
+            following,
+            blocking,
+        };
+        self.publish(Content::Plain(contact))
+    }
+
+    /// Publish a [Vote] on the message `link`.
+    pub fn vote(&mut self, link: Multihash, value: i32, expression: &str) -> Result<Vec<u8>> {
+        let vote = Vote {
+            vote: VoteValue {
+                link,
+                value,
+                expression: expression.to_owned(),
+            },
+        };
+        self.publish(Content::Plain(vote))
+    }
+
+    /// Publish an [About] describing this feed. The fields that are `None` are left as they were.
+    pub fn about(
+        &mut self,
+        name: Option<&str>,
+        description: Option<&str>,
+        image: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let about = About {
+            about: author_of(self.feed.public_key()),
+            name: name.map(ToOwned::to_owned),
+            description: description.map(ToOwned::to_owned),
+            image: image.map(ToOwned::to_owned),
+        };
+        self.publish(Content::Plain(about))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        content_as, content_type, validate_feed, About, Content, ParsedMessage, Post, Publisher,
+        Vote,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn publisher_publishes_mixed_messages() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (friend, _) = generate_longterm_keypair();
+        let friend_id = crate::author_of(&friend);
+
+        let mut publisher = Publisher::new(pk, sk);
+        let mut messages = vec![
+            publisher.about(Some("alice"), Some("hi"), None).unwrap(),
+            publisher.post("hello").unwrap(),
+            publisher.contact(friend_id, true, false).unwrap(),
+        ];
+        let liked = ParsedMessage::from_bytes(&messages[1]).unwrap().key;
+        messages.push(publisher.vote(liked.clone(), 1, "Like").unwrap());
+        messages.push(
+            publisher
+                .publish(Content::Plain(Post::new("bye").with_channel("ssb")))
+                .unwrap(),
+        );
+
+        assert!(validate_feed(&messages).is_ok());
+        assert_eq!(publisher.latest().unwrap().sequence, 5);
+        let types: Vec<_> = messages
+            .iter()
+            .map(|msg| content_type(msg).unwrap().unwrap())
+            .collect();
+        assert_eq!(types, vec!["about", "post", "contact", "vote", "post"]);
+
+        let about: About = content_as(&messages[0]).unwrap();
+        assert_eq!(about.about, crate::author_of(&pk));
+        let vote: Vote = content_as(&messages[3]).unwrap();
+        assert_eq!(vote.vote.link, liked);
+    }
+}
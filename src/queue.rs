@@ -0,0 +1,105 @@
+//! Composing messages before the keys to publish them are available.
+
+use serde::{Deserialize, Serialize};
+use ssb_crypto::{PublicKey, SecretKey};
+
+use crate::{Content, Feed, Result};
+
+/// Content waiting to be published, in the order it was composed.
+///
+/// The queue can be serialized to keep it between sessions, as long as `T` can be.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PublishQueue<T> {
+    entries: Vec<QueuedContent<T>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct QueuedContent<T> {
+    content: T,
+    timestamp: f64,
+}
+
+impl<T> Default for PublishQueue<T> {
+    fn default() -> PublishQueue<T> {
+        PublishQueue {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T: Serialize> PublishQueue<T> {
+    /// An empty queue.
+    pub fn new() -> PublishQueue<T> {
+        PublishQueue::default()
+    }
+
+    /// Queue plain `content`, to be published with `timestamp`.
+    pub fn enqueue(&mut self, content: T, timestamp: f64) {
+        self.entries.push(QueuedContent { content, timestamp });
+    }
+
+    /// The number of queued messages.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether nothing is queued.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Publish everything in the queue, in order, after `previous` (like [publish](crate::publish)).
+    ///
+    /// Returns the new `{key, value}` messages. The queue is only emptied if every message was
+    /// published, otherwise it is left as it was.
+    pub fn flush<P: AsRef<[u8]>>(
+        &mut self,
+        public_key: &PublicKey,
+        secret_key: &SecretKey,
+        previous_msg_value_bytes: Option<P>,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut feed = Feed::new(*public_key, secret_key.clone());
+        if let Some(previous) = previous_msg_value_bytes {
+            feed.continue_from(previous.as_ref())?;
+        }
+
+        let messages = self
+            .entries
+            .iter()
+            .map(|entry| feed.publish(Content::Plain(&entry.content), entry.timestamp))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.entries.clear();
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{content_as, publish, validate_feed, Content, Post, PublishQueue};
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn flush_a_queue_to_a_chain() {
+        let mut queue = PublishQueue::new();
+        for i in 0..3 {
+            queue.enqueue(Post::new(format!("{}", i)), i as f64);
+        }
+
+        // Keep the queue between sessions.
+        let saved = serde_json::to_string(&queue).unwrap();
+        let mut queue: PublishQueue<Post> = serde_json::from_str(&saved).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        let (pk, sk) = generate_longterm_keypair();
+        let first =
+            publish::<_, &[u8]>(Content::Plain(Post::new("first")), None, &pk, &sk, 0.0).unwrap();
+        let messages = queue.flush(&pk, &sk, Some(&first)).unwrap();
+        assert!(queue.is_empty());
+
+        let feed: Vec<_> = std::iter::once(first).chain(messages).collect();
+        assert_eq!(feed.len(), 4);
+        assert!(validate_feed(&feed).is_ok());
+        assert_eq!(content_as::<Post>(&feed[3]).unwrap(), Post::new("2"));
+    }
+}
@@ -0,0 +1,824 @@
+//! Reading fields back out of published messages.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+
+use serde::de::DeserializeOwned;
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::Deserialize;
+use snafu::{ensure, ResultExt};
+use ssb_legacy_msg_data::json::{from_slice, to_string};
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_legacy_msg_data::LegacyF64;
+use ssb_multiformats::multihash::{Multihash, Target};
+use ssb_multiformats::multikey::Multikey;
+
+use crate::canonical::{self, CanonicalJson};
+use crate::{
+    legacy_length, IndexEntry, InvalidEnvelope, InvalidMessage, InvalidPreviousMessage,
+    LegacyJsonEncodeFailed, PreviousMessageMissingField, Result, SsbMessage, SsbPreviousMessage,
+};
+
+/// The fields of a published message that are needed to publish the next message after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedMessage {
+    /// The message key.
+    pub key: Multihash,
+    /// The feed that published the message.
+    pub author: Multikey,
+    /// The position of the message in its feed, starting at 1.
+    pub sequence: u64,
+    /// The claimed publishing time, in milliseconds since the unix epoch.
+    pub timestamp: LegacyF64,
+}
+
+impl ParsedMessage {
+    /// Parse a `{key, value}` message, such as one returned by [publish](crate::publish).
+    ///
+    /// Fails with [Error::PreviousMessageMissingField](crate::Error::PreviousMessageMissingField)
+    /// naming the first missing field if `msg_bytes` is some other json object.
+    pub fn from_bytes(msg_bytes: &[u8]) -> Result<ParsedMessage> {
+        let decoded = match from_slice::<SsbPreviousMessage>(msg_bytes) {
+            Ok(decoded) => decoded,
+            Err(source) => {
+                if let Some(field) = missing_field(msg_bytes) {
+                    return PreviousMessageMissingField { field }.fail();
+                }
+                return Err(source).context(InvalidPreviousMessage {
+                    message: msg_bytes.to_owned(),
+                });
+            }
+        };
+
+        Ok(ParsedMessage {
+            key: decoded.key,
+            author: decoded.value.author,
+            sequence: decoded.value.sequence,
+            timestamp: decoded.value.timestamp,
+        })
+    }
+}
+
+impl From<IndexEntry> for ParsedMessage {
+    fn from(entry: IndexEntry) -> ParsedMessage {
+        ParsedMessage {
+            key: entry.key,
+            author: entry.author,
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+        }
+    }
+}
+
+// The first of the fields `ParsedMessage` needs that is missing from a json object.
+fn missing_field(msg_bytes: &[u8]) -> Option<&'static str> {
+    let message = match from_slice::<Value>(msg_bytes) {
+        Ok(Value::Object(message)) => message,
+        _ => return None,
+    };
+    if message.get("key").is_none() {
+        return Some("key");
+    }
+    let value = match message.get("value") {
+        Some(Value::Object(value)) => value,
+        Some(_) => return None,
+        None => return Some("value"),
+    };
+
+    ["author", "sequence", "timestamp"]
+        .iter()
+        .find(|field| value.get(field).is_none())
+        .copied()
+}
+
+/// The `{key, value}` message `msg_bytes` trimmed down to what publishing the next message after
+/// it needs, for callers that store or send the previous message between publishes.
+///
+/// Only the `key` and the `author`, `sequence` and `timestamp` of the value are kept. Passing the
+/// result as the previous message publishes exactly the same message as passing all of
+/// `msg_bytes`. The `timestamp` is kept for [ParsedMessage::timestamp], which
+/// [Feed](crate::Feed) remembers as the time of its latest message.
+pub fn minimal_previous(msg_bytes: &[u8]) -> Result<Vec<u8>> {
+    let message = ParsedMessage::from_bytes(msg_bytes)?;
+
+    let mut value = RidiculousStringMap::with_capacity(3);
+    value.insert(
+        "author".to_owned(),
+        Value::String(message.author.to_legacy_string()),
+    );
+    value.insert(
+        "sequence".to_owned(),
+        Value::Float(LegacyF64::from_f64(message.sequence as f64).unwrap()),
+    );
+    value.insert("timestamp".to_owned(), Value::Float(message.timestamp));
+
+    let mut minimal = RidiculousStringMap::with_capacity(2);
+    minimal.insert(
+        "key".to_owned(),
+        Value::String(message.key.to_legacy_string()),
+    );
+    minimal.insert("value".to_owned(), Value::Object(value));
+    canonical::DEFAULT.encode_value(&Value::Object(minimal))
+}
+
+/// The message as the string javascript's `JSON.stringify` produces for it.
+///
+/// This is for display, and for comparing against the output of javascript tools as text. It is
+/// _not_ the encoding that is signed and hashed, which is indented.
+pub fn to_canonical_string(msg_bytes: &[u8]) -> Result<String> {
+    let value = from_slice::<Value>(msg_bytes).context(InvalidMessage)?;
+    to_string(&value, true)
+        .map_err(|_| snafu::NoneError)
+        .context(LegacyJsonEncodeFailed)
+}
+
+/// The size of a `{key, value}` message's value as counted against [MAX_MESSAGE_SIZE](crate::MAX_MESSAGE_SIZE).
+///
+/// This is the length javascript gives the signing encoding of the value: the size after escaping,
+/// in utf-16 code units, so an emoji counts twice and an escaped quote counts twice.
+pub fn message_size(msg_bytes: &[u8]) -> Result<usize> {
+    let message = from_slice::<SsbMessage>(msg_bytes).context(InvalidMessage)?;
+    let value_bytes = canonical::DEFAULT.encode_value(&message.value)?;
+    Ok(legacy_length(&value_bytes))
+}
+
+/// The bytes that were signed to make the `signature` of a `{key, value}` message.
+///
+/// These are the signing encoding of the message value without its `signature` entry, the same
+/// bytes [encode_signable](crate::encode_signable) produces while publishing. Check them against
+/// the signature and the `author` key to verify a message from elsewhere.
+pub fn recover_signable(msg_bytes: &[u8]) -> Result<Vec<u8>> {
+    let message = from_slice::<SsbMessage>(msg_bytes).context(InvalidMessage)?;
+    let value = match &message.value {
+        Value::Object(value) => value,
+        _ => {
+            return InvalidEnvelope {
+                reason: "`value` must be an object",
+            }
+            .fail()
+        }
+    };
+    ensure!(
+        value.get("signature").is_some(),
+        InvalidEnvelope {
+            reason: "`signature` is missing"
+        }
+    );
+
+    // Rebuilt rather than removed from, to keep the order of the remaining entries.
+    let mut signable = RidiculousStringMap::with_capacity(value.len());
+    for (key, value) in value.iter().filter(|(key, _)| *key != "signature") {
+        signable.insert(key.clone(), value.clone());
+    }
+    canonical::DEFAULT.encode_value(&Value::Object(signable))
+}
+
+/// Whether the content of a `{key, value}` message is encrypted, a string ending in `.box`.
+///
+/// Plain content is skipped over without being decoded.
+pub fn is_encrypted(msg_bytes: &[u8]) -> Result<bool> {
+    from_slice::<EncryptedMessage>(msg_bytes)
+        .map(|msg| msg.value.content.0)
+        .context(InvalidMessage)
+}
+
+#[derive(Deserialize)]
+struct EncryptedMessage {
+    value: EncryptedValue,
+}
+
+#[derive(Deserialize)]
+struct EncryptedValue {
+    content: Encrypted,
+}
+
+struct Encrypted(bool);
+
+impl<'de> Deserialize<'de> for Encrypted {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Encrypted, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(EncryptedVisitor)
+    }
+}
+
+struct EncryptedVisitor;
+
+impl<'de> Visitor<'de> for EncryptedVisitor {
+    type Value = Encrypted;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a content object or an encrypted string")
+    }
+
+    fn visit_str<E: de::Error>(self, content: &str) -> std::result::Result<Encrypted, E> {
+        Ok(Encrypted(content.ends_with(".box")))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<Encrypted, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(Encrypted(false))
+    }
+}
+
+/// Decode the content of a `{key, value}` message as a `T`, such as a [Post](crate::Post).
+///
+/// Fails for encrypted content, and for content of other types (if `T` checks its `type`).
+pub fn content_as<T: DeserializeOwned>(msg_bytes: &[u8]) -> Result<T> {
+    from_slice::<ContentMessage<T>>(msg_bytes)
+        .map(|msg| msg.value.content)
+        .context(InvalidMessage)
+}
+
+#[derive(Deserialize)]
+struct ContentMessage<T> {
+    value: ContentValue<T>,
+}
+
+#[derive(Deserialize)]
+struct ContentValue<T> {
+    content: T,
+}
+
+/// The ids referenced by a message's content, see [extract_references].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct References {
+    /// Referenced feeds (`@` ids).
+    pub feeds: BTreeSet<Multikey>,
+    /// Referenced messages (`%` ids).
+    pub messages: BTreeSet<Multihash>,
+    /// Referenced blobs (`&` ids).
+    pub blobs: BTreeSet<Multihash>,
+}
+
+/// Collect every feed, message and blob id in the content of a `{key, value}` message.
+///
+/// The content is searched recursively for strings that are entirely an id, whatever the content
+/// type. Ids inside longer strings, such as the text of a post, are not found. Encrypted content
+/// references nothing.
+pub fn extract_references(msg_bytes: &[u8]) -> Result<References> {
+    let message = from_slice::<SsbMessage>(msg_bytes).context(InvalidMessage)?;
+
+    let mut references = References::default();
+    if let Value::Object(value) = &message.value {
+        if let Some(content @ Value::Object(_)) = value.get("content") {
+            collect_references(content, &mut references);
+        }
+    }
+
+    Ok(references)
+}
+
+fn collect_references(value: &Value, references: &mut References) {
+    match value {
+        Value::String(s) => {
+            if let Ok((feed, [])) = Multikey::from_legacy(s.as_bytes()) {
+                references.feeds.insert(feed);
+            } else if let Ok((hash, [])) = Multihash::from_legacy(s.as_bytes()) {
+                match hash.0 {
+                    Target::Message => references.messages.insert(hash),
+                    Target::Blob => references.blobs.insert(hash),
+                };
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_references(value, references);
+            }
+        }
+        Value::Object(map) => {
+            for (_, value) in map.iter() {
+                collect_references(value, references);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// The `content` of a `{key, value}` message without the rest of the message: a json object, or
+/// for encrypted content a json string.
+///
+/// The content is encoded the same way as in the message. It can be published again with
+/// [publish_raw_content](crate::publish_raw_content).
+pub fn extract_content_bytes(msg_bytes: &[u8]) -> Result<Vec<u8>> {
+    let message = from_slice::<SsbMessage>(msg_bytes).context(InvalidMessage)?;
+    let content = match &message.value {
+        Value::Object(value) => value.get("content"),
+        _ => {
+            return InvalidEnvelope {
+                reason: "`value` must be an object",
+            }
+            .fail()
+        }
+    };
+    match content {
+        Some(content) => canonical::DEFAULT.encode_value(content),
+        None => InvalidEnvelope {
+            reason: "`content` is missing",
+        }
+        .fail(),
+    }
+}
+
+/// The `type` of a message's content, or `None` if the content is encrypted.
+///
+/// Only the `type` entry of the content is decoded, the rest of the message is skipped over.
+pub fn content_type(msg_bytes: &[u8]) -> Result<Option<String>> {
+    from_slice::<ContentTypeMessage>(msg_bytes)
+        .map(|msg| msg.value.content.0)
+        .context(InvalidMessage)
+}
+
+/// The number of messages of each content type in a feed, see [type_histogram].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TypeHistogram {
+    /// Messages by content `type`, with encrypted messages under `"encrypted"`.
+    pub types: BTreeMap<String, u64>,
+    /// Plain messages whose content `type` is `"encrypted"`, which are not in [types](Self::types).
+    pub plain_typed_encrypted: u64,
+}
+
+/// The number of messages of each content `type` in `messages`.
+///
+/// Only the content types are decoded, with [content_type], one message at a time. Plain content
+/// may have any `type`, even `"encrypted"`, so it is counted apart from encrypted content.
+pub fn type_histogram<I>(messages: I) -> Result<TypeHistogram>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut histogram = TypeHistogram::default();
+    for message in messages {
+        let content_type = match content_type(message.as_ref())? {
+            Some(content_type) if content_type == "encrypted" => {
+                histogram.plain_typed_encrypted += 1;
+                continue;
+            }
+            Some(content_type) => content_type,
+            None => "encrypted".to_owned(),
+        };
+        *histogram.types.entry(content_type).or_insert(0) += 1;
+    }
+    Ok(histogram)
+}
+
+/// The ranges of sequence numbers missing from a feed.
+///
+/// `messages` may yield the `{key, value}` messages of a single feed in any order. Only their
+/// sequence numbers are read, nothing is validated. A feed starts at sequence 1, so if the first
+/// messages are missing the first range starts at 1. Nothing after the last message is reported.
+pub fn find_gaps<I>(messages: I) -> Result<Vec<Range<u64>>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut sequences = messages
+        .into_iter()
+        .map(|msg| {
+            from_slice::<SequenceMessage>(msg.as_ref())
+                .map(|msg| msg.value.sequence)
+                .context(InvalidMessage)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    sequences.sort_unstable();
+    sequences.dedup();
+
+    let mut gaps = Vec::new();
+    let mut expected = 1;
+    for sequence in sequences {
+        if sequence > expected {
+            gaps.push(expected..sequence);
+        }
+        expected = sequence + 1;
+    }
+
+    Ok(gaps)
+}
+
+/// Whether a `{key, value}` message comes after a trusted checkpoint, the sequence number
+/// `checkpoint_seq`.
+///
+/// Only the sequence number is read. Verify the message's signature and author as well before
+/// accepting it as new.
+pub fn is_after_checkpoint(msg_bytes: &[u8], checkpoint_seq: u64) -> Result<bool> {
+    from_slice::<SequenceMessage>(msg_bytes)
+        .map(|msg| msg.value.sequence > checkpoint_seq)
+        .context(InvalidMessage)
+}
+
+#[derive(Deserialize)]
+struct SequenceMessage {
+    value: SequenceValue,
+}
+
+#[derive(Deserialize)]
+struct SequenceValue {
+    sequence: u64,
+}
+
+#[derive(Deserialize)]
+struct ContentTypeMessage {
+    value: ContentTypeValue,
+}
+
+#[derive(Deserialize)]
+struct ContentTypeValue {
+    content: ContentType,
+}
+
+// The `type` of plain content, or `None` for encrypted (string) content.
+struct ContentType(Option<String>);
+
+impl<'de> Deserialize<'de> for ContentType {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<ContentType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ContentTypeVisitor)
+    }
+}
+
+struct ContentTypeVisitor;
+
+impl<'de> Visitor<'de> for ContentTypeVisitor {
+    type Value = ContentType;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a content object or an encrypted string")
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> std::result::Result<ContentType, E> {
+        Ok(ContentType(None))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<ContentType, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut content_type = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "type" {
+                content_type = Some(map.next_value::<String>()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        match content_type {
+            Some(content_type) => Ok(ContentType(Some(content_type))),
+            None => Err(de::Error::missing_field("type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{example_contact, EXAMPLE_FEED_ID};
+    use crate::{
+        blob_id, content_as, content_type, encrypt_to_groups, extract_references, find_gaps,
+        is_after_checkpoint, is_encrypted, message_size, minimal_previous, parse_signature,
+        publish, publish_post, recover_signable, to_canonical_string, type_histogram, Contact,
+        Content, Error, Feed, Mention, ParsedMessage, Post, SsbMessage, MAX_MESSAGE_SIZE,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::json::from_slice;
+    use ssb_legacy_msg_data::value::Value;
+    use ssb_multiformats::multibox::Multibox;
+    use ssb_multiformats::multikey::Multikey;
+
+    #[test]
+    fn canonical_string_matches_json_stringify() {
+        let msg = r##"{
+  "key": "%kmXb3MXtBJaNugcEL/Q7G40DgcAkMNTj3yhmxKHjfCM=.sha256",
+  "value": {
+    "previous": null,
+    "author": "@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519",
+    "sequence": 1,
+    "timestamp": 1470186877575.5,
+    "hash": "sha256",
+    "content": {
+      "1": 0.5,
+      "type": "post",
+      "text": "\"quoted\" \\ back\nslash é 😀 \u0001"
+    },
+    "signature": "QJKWui3oyK6r5dH13xHkEVFhfMZDTXfK2tW21nyfheFClSf69yYK77Itj1BGcOimZ16pj9u3tMArLUCGSscqCQ==.sig.ed25519"
+  }
+}"##;
+        // Produced by `JSON.stringify(JSON.parse(msg))` in node.
+        let stringified = r##"{"key":"%kmXb3MXtBJaNugcEL/Q7G40DgcAkMNTj3yhmxKHjfCM=.sha256","value":{"previous":null,"author":"@EnPSnV1HZdyE7pcKxqukyhmnwE9076RtAlYclaUMX5g=.ed25519","sequence":1,"timestamp":1470186877575.5,"hash":"sha256","content":{"1":0.5,"type":"post","text":"\"quoted\" \\ back\nslash é 😀 \u0001"},"signature":"QJKWui3oyK6r5dH13xHkEVFhfMZDTXfK2tW21nyfheFClSf69yYK77Itj1BGcOimZ16pj9u3tMArLUCGSscqCQ==.sig.ed25519"}}"##;
+
+        assert_eq!(to_canonical_string(msg.as_bytes()).unwrap(), stringified);
+    }
+
+    #[test]
+    fn content_type_of_plain_and_encrypted_messages() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let contact = example_contact(true);
+        let plain = publish::<_, &[u8]>(Content::Plain(contact), None, &pk, &sk, 0.0).unwrap();
+        assert_eq!(content_type(&plain).unwrap(), Some("contact".to_string()));
+
+        let boxed = Multibox::new_private_box(vec![0; 128]);
+        let encrypted = publish(
+            Content::<Contact>::Encrypted(boxed),
+            Some(&plain),
+            &pk,
+            &sk,
+            1.0,
+        )
+        .unwrap();
+        assert_eq!(content_type(&encrypted).unwrap(), None);
+    }
+
+    #[test]
+    fn index_entries_name_their_message() {
+        use crate::publish_indexed;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let post = Content::Plain(Post::new("hi"));
+        let (msg, entry) = publish_indexed::<_, &[u8]>(post, None, &pk, &sk, 0.0).unwrap();
+        assert_eq!(
+            ParsedMessage::from(entry),
+            ParsedMessage::from_bytes(&msg).unwrap()
+        );
+    }
+
+    #[test]
+    fn size_check_counts_escaped_characters() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        // 2000 characters, but 3500 once escaped and counted in utf-16.
+        let text = "\"\\😀".repeat(500) + &"a".repeat(500);
+        let msg = publish_post::<&[u8]>(Post::new(text.clone()), None, &pk, &sk, 0.0).unwrap();
+
+        let value = match from_slice::<Value>(&msg).unwrap() {
+            Value::Object(msg) => msg.get("value").unwrap().clone(),
+            _ => panic!("message is not an object"),
+        };
+        let encoded = ssb_legacy_msg_data::json::to_string(&value, false).unwrap();
+        assert_eq!(message_size(&msg).unwrap(), encoded.encode_utf16().count());
+        assert!(message_size(&msg).unwrap() > text.chars().count() + 1500);
+
+        // Short enough by character count, too long once escaped.
+        let text = "\"".repeat(MAX_MESSAGE_SIZE / 2);
+        match publish_post::<&[u8]>(Post::new(text), None, &pk, &sk, 0.0) {
+            Err(Error::MessageTooLarge { size }) => assert!(size > MAX_MESSAGE_SIZE),
+            other => panic!("expected the message to be too large, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn find_gaps_in_a_partial_feed() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for i in 0..8 {
+            let post = Content::Plain(Post::new(format!("{}", i)));
+            let msg = publish(post, feed.last(), &pk, &sk, i as f64).unwrap();
+            feed.push(msg);
+        }
+        assert!(find_gaps(&feed).unwrap().is_empty());
+
+        // Keep sequences 2, 3, 6 and 8, out of order.
+        let partial = vec![&feed[5], &feed[1], &feed[7], &feed[2]];
+        assert_eq!(find_gaps(partial).unwrap(), vec![1..2, 4..6, 7..8]);
+    }
+
+    #[test]
+    fn content_as_reads_typed_content() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let post = Content::Plain(Post::new("hi"));
+        let msg = publish::<_, &[u8]>(post, None, &pk, &sk, 0.0).unwrap();
+        assert_eq!(content_as::<Post>(&msg).unwrap(), Post::new("hi"));
+        assert!(content_as::<Contact>(&msg).is_err());
+    }
+
+    #[test]
+    fn is_encrypted_of_plain_and_encrypted_messages() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let plain =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        assert!(!is_encrypted(&plain).unwrap());
+
+        let boxed = Multibox::new_private_box(vec![0; 128]);
+        let encrypted = publish(
+            Content::<Post>::Encrypted(boxed),
+            Some(&plain),
+            &pk,
+            &sk,
+            1.0,
+        )
+        .unwrap();
+        assert!(is_encrypted(&encrypted).unwrap());
+
+        assert!(is_encrypted(b"{}").is_err());
+    }
+
+    #[test]
+    fn extract_references_from_a_post() {
+        let (pk, sk) = generate_longterm_keypair();
+        let feed = EXAMPLE_FEED_ID;
+        let parent =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        let parent = ParsedMessage::from_bytes(&parent).unwrap().key;
+        let blob = blob_id(b"a blob");
+
+        let mut post = Post::new(format!("mentions {} inline", feed))
+            .with_recps(&[feed])
+            .unwrap();
+        for link in &[parent.to_legacy_string(), blob.to_legacy_string()] {
+            post.mentions.push(Mention {
+                link: link.clone(),
+                name: None,
+                mime: None,
+                size: None,
+            });
+        }
+        let msg = publish::<_, &[u8]>(Content::Plain(post), None, &pk, &sk, 0.0).unwrap();
+
+        let references = extract_references(&msg).unwrap();
+        assert_eq!(
+            references.feeds.into_iter().collect::<Vec<_>>(),
+            vec![Multikey::from_legacy(feed.as_bytes()).unwrap().0]
+        );
+        assert_eq!(
+            references.messages.into_iter().collect::<Vec<_>>(),
+            vec![parent]
+        );
+        assert_eq!(references.blobs.into_iter().collect::<Vec<_>>(), vec![blob]);
+    }
+
+    #[test]
+    fn previous_missing_fields_are_named() {
+        let (pk, sk) = generate_longterm_keypair();
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+        let msg = match from_slice::<Value>(&msg).unwrap() {
+            Value::Object(msg) => msg,
+            _ => panic!("message is not an object"),
+        };
+
+        let without = |field: &str| {
+            let mut msg = msg.clone();
+            if msg.remove(field.to_string()).is_none() {
+                if let Some(Value::Object(value)) = msg.get_mut("value") {
+                    value.remove(field.to_string()).unwrap();
+                }
+            }
+            ssb_legacy_msg_data::json::to_vec(&Value::Object(msg), false).unwrap()
+        };
+
+        for field in &["key", "value", "author", "sequence", "timestamp"] {
+            let previous = without(field);
+            match publish(
+                Content::Plain(Post::new("two")),
+                Some(&previous),
+                &pk,
+                &sk,
+                1.0,
+            ) {
+                Err(Error::PreviousMessageMissingField { field: missing }) => {
+                    assert_eq!(missing, *field)
+                }
+                other => panic!("expected `{}` to be missing, got {:?}", field, other),
+            }
+        }
+
+        match publish(
+            Content::Plain(Post::new("two")),
+            Some(b"[1, 2]"),
+            &pk,
+            &sk,
+            1.0,
+        ) {
+            Err(Error::InvalidPreviousMessage { .. }) => (),
+            other => panic!("expected an invalid previous message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn messages_are_checked_against_a_checkpoint() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut feed = Feed::new(pk, sk);
+        let messages: Vec<Vec<u8>> = (0..3)
+            .map(|i| {
+                feed.publish(Content::Plain(Post::new("hi")), i as f64)
+                    .unwrap()
+            })
+            .collect();
+
+        assert!(!is_after_checkpoint(&messages[0], 2).unwrap());
+        assert!(!is_after_checkpoint(&messages[1], 2).unwrap());
+        assert!(is_after_checkpoint(&messages[2], 2).unwrap());
+        assert!(is_after_checkpoint(&messages[0], 0).unwrap());
+    }
+
+    #[test]
+    fn recovered_signable_verifies() {
+        let (pk, sk) = generate_longterm_keypair();
+        let msg =
+            publish::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+
+        let signable = recover_signable(&msg).unwrap();
+        let value = match from_slice::<SsbMessage>(&msg).unwrap().value {
+            Value::Object(value) => value,
+            _ => panic!("value is not an object"),
+        };
+        let author = match value.get("author") {
+            Some(Value::String(author)) => Multikey::from_legacy(author.as_bytes()).unwrap().0,
+            _ => panic!("author is not a string"),
+        };
+        let signature = match value.get("signature") {
+            Some(Value::String(signature)) => parse_signature(&author, signature).unwrap(),
+            _ => panic!("signature is not a string"),
+        };
+
+        assert!(author.is_signature_correct(&signable, &signature));
+        assert!(!author.is_signature_correct(&signable[1..], &signature));
+    }
+
+    #[test]
+    fn minimal_previous_publishes_the_same_message() {
+        let (pk, sk) = generate_longterm_keypair();
+        let first = publish(Content::Plain(Post::new("1")), None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+        let minimal = minimal_previous(&first).unwrap();
+        assert!(minimal.len() < first.len());
+        assert_eq!(
+            ParsedMessage::from_bytes(&minimal).unwrap(),
+            ParsedMessage::from_bytes(&first).unwrap()
+        );
+
+        let post = || Content::Plain(Post::new("2"));
+        let full = publish(post(), Some(&first), &pk, &sk, 1.0).unwrap();
+        let trimmed = publish(post(), Some(&minimal), &pk, &sk, 1.0).unwrap();
+        assert_eq!(full, trimmed);
+    }
+
+    #[test]
+    fn content_types_are_counted() {
+        use crate::content::to_value;
+        use std::collections::BTreeMap;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let contact = Contact {
+            contact: crate::author_of(&pk),
+            following: true,
+            blocking: false,
+        };
+        let mut private =
+            encrypt_to_groups(&Post::new("secret"), &[vec![crate::author_of(&pk)]]).unwrap();
+        let mut fake_private = BTreeMap::new();
+        fake_private.insert("type", "encrypted");
+
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for content in [
+            Content::Plain(to_value(&Post::new("one")).unwrap()),
+            Content::Plain(to_value(&contact).unwrap()),
+            Content::Plain(to_value(&Post::new("two")).unwrap()),
+            Content::Plain(to_value(&fake_private).unwrap()),
+            match private.pop().unwrap() {
+                Content::Encrypted(boxed) => Content::Encrypted(boxed),
+                Content::Plain(_) => panic!("content was not encrypted"),
+            },
+        ]
+        .iter()
+        .cloned()
+        {
+            let msg = publish(content, feed.last(), &pk, &sk, feed.len() as f64).unwrap();
+            feed.push(msg);
+        }
+
+        let histogram = type_histogram(&feed).unwrap();
+        let types: Vec<(&str, u64)> = histogram
+            .types
+            .iter()
+            .map(|(t, n)| (t.as_str(), *n))
+            .collect();
+        assert_eq!(types, [("contact", 1), ("encrypted", 1), ("post", 2)]);
+        assert_eq!(histogram.plain_typed_encrypted, 1);
+    }
+
+    #[test]
+    fn genesis_messages_have_a_null_previous() {
+        // Javascript writes `"previous": null` instead of leaving the entry out, and the key is
+        // the hash of the value with that entry.
+        let (pk, sk) = generate_longterm_keypair();
+        let post = Content::Plain(Post::new("first"));
+        let msg = publish(post, None::<&[u8]>, &pk, &sk, 0.0).unwrap();
+
+        let stringified = to_canonical_string(&msg).unwrap();
+        assert!(stringified.contains(r#""value":{"previous":null,"author":"@"#));
+
+        let signable = recover_signable(&msg).unwrap();
+        let signable = std::str::from_utf8(&signable).unwrap();
+        assert!(signable.starts_with("{\n  \"previous\": null,\n  \"author\": \"@"));
+    }
+}
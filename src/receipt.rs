@@ -0,0 +1,127 @@
+//! Compact, signed proofs that a message was published.
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_legacy_msg_data::LegacyF64;
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::{Multikey, Multisig};
+
+use crate::canonical::{self, CanonicalJson};
+use crate::{publish_indexed, sign, Content, Result};
+
+/// A receipt for a published message, signed by its author.
+///
+/// It can be checked with [Receipt::verify] without the message itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Receipt {
+    /// The message key.
+    pub key: Multihash,
+    /// The position of the message in its feed, starting at 1.
+    pub sequence: u64,
+    /// The feed that published the message.
+    pub author: Multikey,
+    /// The claimed publishing time, in milliseconds since the unix epoch.
+    pub timestamp: LegacyF64,
+    /// The author's signature of the other fields.
+    pub signature: Multisig,
+}
+
+impl Receipt {
+    /// Whether the receipt was signed by `author`.
+    pub fn verify(&self, author: &Multikey) -> bool {
+        if *author != self.author {
+            return false;
+        }
+
+        match signable_bytes(&self.key, self.sequence, &self.author, self.timestamp) {
+            Ok(bytes) => author.is_signature_correct(&bytes, &self.signature),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Publish a new message, like [publish](crate::publish), also returning a [Receipt] for it.
+pub fn publish_receipt<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<(Vec<u8>, Receipt)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let (message, entry) = publish_indexed(
+        content,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )?;
+
+    let bytes = signable_bytes(&entry.key, entry.sequence, &entry.author, entry.timestamp)?;
+    let receipt = Receipt {
+        key: entry.key,
+        sequence: entry.sequence,
+        author: entry.author,
+        timestamp: entry.timestamp,
+        signature: sign(&bytes, secret_key),
+    };
+
+    Ok((message, receipt))
+}
+
+// The signing encoding of `{key, sequence, author, timestamp}`.
+fn signable_bytes(
+    key: &Multihash,
+    sequence: u64,
+    author: &Multikey,
+    timestamp: LegacyF64,
+) -> Result<Vec<u8>> {
+    let mut map = RidiculousStringMap::with_capacity(4);
+    map.insert("key".to_owned(), Value::String(key.to_legacy_string()));
+    map.insert(
+        "sequence".to_owned(),
+        // Sequences are far below 2^53, so this is exact.
+        Value::Float(LegacyF64::from_f64(sequence as f64).unwrap()),
+    );
+    map.insert(
+        "author".to_owned(),
+        Value::String(author.to_legacy_string()),
+    );
+    map.insert("timestamp".to_owned(), Value::Float(timestamp));
+
+    canonical::DEFAULT.encode_value(&Value::Object(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{publish_receipt, Content, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_multiformats::multikey::Multikey;
+    use std::convert::TryInto;
+
+    #[test]
+    fn receipts_verify_under_the_author_key() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (other_pk, _) = generate_longterm_keypair();
+
+        let (msg, receipt) =
+            publish_receipt::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0)
+                .unwrap();
+        let parsed = ParsedMessage::from_bytes(&msg).unwrap();
+        assert_eq!(receipt.key, parsed.key);
+        assert_eq!(receipt.sequence, 1);
+
+        assert!(receipt.verify(&parsed.author));
+        assert!(!receipt.verify(&Multikey::from_ed25519(
+            other_pk.as_ref().try_into().unwrap()
+        )));
+
+        let mut forged = receipt.clone();
+        forged.sequence = 2;
+        assert!(!forged.verify(&parsed.author));
+    }
+}
@@ -0,0 +1,152 @@
+//! Checking ids the way the javascript `ssb-ref` module does.
+
+use snafu::OptionExt;
+
+use crate::{Result, UnknownRefFormat};
+
+/// The length of an ed25519 feed id: the `@` sigil, 44 characters of padded base64 and the
+/// `.ed25519` suffix.
+pub const ED25519_FEED_ID_LENGTH: usize = 1 + 44 + 8;
+
+/// Whether `s` is a message id as javascript's `ssb-ref` `isMsg` accepts it, eg.
+/// `%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256`.
+///
+/// This is stricter than parsing a [Multihash](crate::Multihash): the id must be exactly the
+/// sigil, 44 characters of padded standard base64 and the `.sha256` suffix, with nothing around
+/// them.
+pub fn is_valid_msg_ref(s: &str) -> bool {
+    match s.strip_prefix('%').and_then(|s| s.strip_suffix(".sha256")) {
+        Some(key) => is_base64_key(key),
+        None => false,
+    }
+}
+
+/// Whether `s` is an ed25519 feed id in the form javascript's `ssb-ref` `isFeed` accepts, eg.
+/// `@9Zf0se86PotjNqaOt9ue8BNBLkGVLQcLNDw/pRQHY3U=.ed25519`.
+pub fn is_valid_feed_ref(s: &str) -> bool {
+    match s.strip_prefix('@').and_then(|s| s.strip_suffix(".ed25519")) {
+        Some(key) => is_base64_key(key),
+        None => false,
+    }
+}
+
+/// The feed formats a feed or message id can belong to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    /// The classic format this crate publishes: `.ed25519` feeds and `.sha256` messages.
+    Classic,
+    /// [Gabby Grove](https://github.com/ssbc/ssb-gabbygrove): `.ggfeed-v1` feeds and `.ggmsg-v1`
+    /// messages.
+    GabbyGrove,
+    /// [Bendy Butt](https://github.com/ssb-ngi-pointer/bendy-butt-spec): `.bbfeed-v1` feeds and
+    /// `.bbmsg-v1` messages.
+    BendyButt,
+}
+
+// The feed format of each id suffix.
+const FORMAT_SUFFIXES: [(&str, FeedFormat); 6] = [
+    (".ed25519", FeedFormat::Classic),
+    (".sha256", FeedFormat::Classic),
+    (".ggfeed-v1", FeedFormat::GabbyGrove),
+    (".ggmsg-v1", FeedFormat::GabbyGrove),
+    (".bbfeed-v1", FeedFormat::BendyButt),
+    (".bbmsg-v1", FeedFormat::BendyButt),
+];
+
+/// The format of the feed that the feed or message id `ref_str` belongs to, from its suffix, eg.
+/// to find where to resolve the `root` of a reply in a mix of feed formats.
+///
+/// Only the suffix is looked at. Fails with
+/// [Error::UnknownRefFormat](crate::Error::UnknownRefFormat) for any other suffix.
+pub fn ref_format(ref_str: &str) -> Result<FeedFormat> {
+    FORMAT_SUFFIXES
+        .iter()
+        .find(|(suffix, _)| ref_str.ends_with(suffix))
+        .map(|&(_, format)| format)
+        .context(UnknownRefFormat { reference: ref_str })
+}
+
+// 32 bytes in padded standard base64: 43 characters and a single `=`.
+fn is_base64_key(s: &str) -> bool {
+    s.len() == 44
+        && s.ends_with('=')
+        && s[..43]
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::EXAMPLE_FEED_ID;
+    use crate::{
+        is_valid_feed_ref, is_valid_msg_ref, publish_indexed, ref_format, Content, Error,
+        FeedFormat, Post, ED25519_FEED_ID_LENGTH,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn msg_refs_are_checked_like_ssb_ref() {
+        let (pk, sk) = generate_longterm_keypair();
+        let (_, entry) =
+            publish_indexed::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0)
+                .unwrap();
+        assert!(is_valid_msg_ref(&entry.key.to_legacy_string()));
+        assert!(is_valid_feed_ref(&entry.author.to_legacy_string()));
+
+        let key = "%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256";
+        assert!(is_valid_msg_ref(key));
+        for malformed in &[
+            "",
+            "drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256",
+            "&drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256",
+            "%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo.sha256",
+            "%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q_NQppMtlo=.sha256",
+            "%drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha512",
+            " %drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=.sha256",
+        ] {
+            assert!(!is_valid_msg_ref(malformed), "{} was accepted", malformed);
+        }
+        assert!(!is_valid_feed_ref(key));
+    }
+
+    #[test]
+    fn author_ids_have_the_ed25519_length() {
+        let known = EXAMPLE_FEED_ID;
+        assert_eq!(ED25519_FEED_ID_LENGTH, 53);
+        assert_eq!(known.len(), ED25519_FEED_ID_LENGTH);
+
+        let (pk, sk) = generate_longterm_keypair();
+        let (_, entry) =
+            publish_indexed::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0)
+                .unwrap();
+        assert_eq!(
+            entry.author.to_legacy_string().len(),
+            ED25519_FEED_ID_LENGTH
+        );
+    }
+
+    #[test]
+    fn ref_formats_come_from_the_suffix() {
+        let key = "drbzMnKEwQk2kWFfpyShHRl6ngPZ48bA1Q/NQppMtlo=";
+        for (suffix, format) in &[
+            ("ed25519", FeedFormat::Classic),
+            ("sha256", FeedFormat::Classic),
+            ("ggfeed-v1", FeedFormat::GabbyGrove),
+            ("ggmsg-v1", FeedFormat::GabbyGrove),
+            ("bbfeed-v1", FeedFormat::BendyButt),
+            ("bbmsg-v1", FeedFormat::BendyButt),
+        ] {
+            assert_eq!(
+                ref_format(&format!("%{}.{}", key, suffix)).unwrap(),
+                *format
+            );
+        }
+
+        for reference in &["%abc.sha512", "@abc.ed25519x", "abc", ""] {
+            match ref_format(reference) {
+                Err(Error::UnknownRefFormat { reference: r }) => assert_eq!(&r, reference),
+                other => panic!("expected UnknownRefFormat, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Re-publishing signature-checked messages from other feeds, eg. in a gateway.
+
+use serde::Serialize;
+
+use crate::validate;
+use crate::{Content, Feed, Result};
+
+/// Check the signature of each incoming message and publish what `transform` makes of it to
+/// `feed`, writing the new messages to `writer`.
+///
+/// `incoming` may yield the `{key, value}` messages of any feeds, eg. as they arrive over the
+/// network. They are handled one at a time, so the stream is never buffered. `transform` returns
+/// the content to publish for a signature-checked message, or `None` to skip it.
+///
+/// Only signatures are checked. The messages of a feed may be interleaved with others or arrive
+/// without their predecessors, so they are not checked against the previous message of their feed;
+/// use [validate_feed](crate::validate_feed) for that.
+///
+/// Stops at the first message with an invalid signature or that can't be published. The messages
+/// published before it have already been written. Returns how many messages were published.
+pub fn relay<I, F, T, W>(
+    incoming: I,
+    feed: &mut Feed,
+    mut transform: F,
+    writer: &mut W,
+) -> Result<usize>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+    F: FnMut(&[u8]) -> Result<Option<(Content<T>, f64)>>,
+    T: Serialize,
+    W: Extend<Vec<u8>>,
+{
+    let mut published = 0;
+
+    for message in incoming {
+        let message = message.as_ref();
+        validate::check_signature(message)?;
+
+        if let Some((content, timestamp)) = transform(message)? {
+            writer.extend(Some(feed.publish(content, timestamp)?));
+            published += 1;
+        }
+    }
+
+    Ok(published)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{content_as, publish, relay, validate_feed, Content, Error, Feed, Post};
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn relay_checks_signatures_and_republishes() {
+        let (their_pk, their_sk) = generate_longterm_keypair();
+        let (pk, sk) = generate_longterm_keypair();
+
+        let mut incoming: Vec<Vec<u8>> = Vec::new();
+        for i in 0..3 {
+            let post = Content::Plain(Post::new(format!("{}", i)));
+            incoming.push(publish(post, incoming.last(), &their_pk, &their_sk, i as f64).unwrap());
+        }
+
+        // Re-publish every post but the first, quoting it.
+        let quote = |msg: &[u8]| -> crate::Result<Option<(Content<Post>, f64)>> {
+            let post = content_as::<Post>(msg)?;
+            if post.text == "0" {
+                return Ok(None);
+            }
+            Ok(Some((
+                Content::Plain(Post::new(format!("> {}", post.text))),
+                0.0,
+            )))
+        };
+
+        let mut feed = Feed::new(pk, sk.clone());
+        let mut relayed: Vec<Vec<u8>> = Vec::new();
+        assert_eq!(relay(&incoming, &mut feed, quote, &mut relayed).unwrap(), 2);
+        assert!(validate_feed(&relayed).is_ok());
+        assert_eq!(content_as::<Post>(&relayed[1]).unwrap(), Post::new("> 2"));
+
+        let tampered = String::from_utf8(incoming[1].clone())
+            .unwrap()
+            .replace(r#""text": "1""#, r#""text": "one""#);
+        let mut feed = Feed::new(pk, sk);
+        match relay(vec![tampered], &mut feed, quote, &mut Vec::new()) {
+            Err(Error::InvalidFeedSignature { .. }) => (),
+            other => panic!("expected the signature to be rejected, got {:?}", other),
+        }
+    }
+}
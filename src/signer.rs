@@ -0,0 +1,282 @@
+//! Signing messages with keys that are kept elsewhere.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use snafu::OptionExt;
+use ssb_crypto::{sign_detached, PublicKey, SecretKey};
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{
+    author_of, legacy_timestamp, parse_previous, publish_parsed_timed, Content, NoKeysForFeed,
+    PublishOptions, Result,
+};
+
+/// Something that signs messages for a feed, such as an in-process key, a hardware security module
+/// or a remote key custody service.
+pub trait Signer {
+    /// The public key of the feed, which must verify the signatures.
+    fn public_key(&self) -> &PublicKey;
+
+    /// The ed25519 signature of `bytes`.
+    ///
+    /// Signers that can fail, eg. over the network, report it with
+    /// [Error::SigningFailed](crate::Error::SigningFailed).
+    fn sign(&self, bytes: &[u8]) -> Result<[u8; 64]>;
+}
+
+/// A [Signer] holding the keys of the feed in memory, as [publish](crate::publish) does.
+#[derive(Debug, Clone)]
+pub struct InProcessSigner {
+    public_key: PublicKey,
+    secret_key: SecretKey,
+}
+
+impl InProcessSigner {
+    /// A signer with the keys of a feed.
+    pub fn new(public_key: PublicKey, secret_key: SecretKey) -> InProcessSigner {
+        InProcessSigner {
+            public_key,
+            secret_key,
+        }
+    }
+}
+
+impl Signer for InProcessSigner {
+    fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    fn sign(&self, bytes: &[u8]) -> Result<[u8; 64]> {
+        Ok(sign_ed25519(bytes, &self.secret_key))
+    }
+}
+
+/// Somewhere the signers of many feeds are kept, such as a server publishing for its users.
+pub trait KeyStore {
+    /// The signer of `feed`, or `None` if this store has no keys for it.
+    ///
+    /// The public key of the signer must be the key of `feed`. The signer may borrow from the
+    /// store, or own a handle such as a connection to a remote signing service.
+    fn keypair_for(&self, feed: &Multikey) -> Result<Option<Box<dyn Signer + '_>>>;
+}
+
+/// A [KeyStore] that keeps the keys of each feed in memory.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKeyStore {
+    signers: HashMap<Multikey, InProcessSigner>,
+}
+
+impl MemoryKeyStore {
+    /// An empty store.
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore::default()
+    }
+
+    /// Add the keys of a feed, replacing any keys it had.
+    pub fn insert(&mut self, public_key: PublicKey, secret_key: SecretKey) {
+        self.signers.insert(
+            author_of(&public_key),
+            InProcessSigner::new(public_key, secret_key),
+        );
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn keypair_for(&self, feed: &Multikey) -> Result<Option<Box<dyn Signer + '_>>> {
+        Ok(self
+            .signers
+            .get(feed)
+            .map(|signer| Box::new(signer.clone()) as Box<dyn Signer>))
+    }
+}
+
+pub(crate) fn sign_ed25519(bytes: &[u8], secret_key: &SecretKey) -> [u8; 64] {
+    let mut sig = [0; 64];
+    sig.copy_from_slice(sign_detached(bytes, secret_key).as_ref());
+    sig
+}
+
+/// Publish a new message, like [publish_with_options](crate::publish_with_options), signed by
+/// `signer`.
+pub fn publish_with_signer<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    signer: &dyn Signer,
+    timestamp: f64,
+    options: &PublishOptions,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    publish_parsed_timed(
+        content,
+        previous.as_ref(),
+        signer,
+        legacy_timestamp(timestamp)?,
+        options,
+        &mut (),
+    )
+    .map(|(message, _)| message)
+}
+
+/// Publish a new message to `feed`, like [publish_with_options](crate::publish_with_options),
+/// signed by its signer in `store`.
+///
+/// Fails with [Error::NoKeysForFeed](crate::Error::NoKeysForFeed) if `store` has no keys for
+/// `feed`.
+pub fn publish_for<T, P, K>(
+    store: &K,
+    feed: &Multikey,
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    timestamp: f64,
+    options: &PublishOptions,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+    K: KeyStore + ?Sized,
+{
+    let signer = store
+        .keypair_for(feed)?
+        .context(NoKeysForFeed { feed: feed.clone() })?;
+    publish_with_signer(
+        content,
+        previous_msg_value_bytes,
+        &*signer,
+        timestamp,
+        options,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        publish, publish_for, publish_with_options, publish_with_signer, validate_feed, Content,
+        ContentOrdering, Error, InProcessSigner, MemoryKeyStore, ParsedMessage, Post,
+        PublishOptions, Signer,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn messages_can_be_signed_by_any_signer() {
+        use ssb_crypto::PublicKey;
+        use std::cell::Cell;
+
+        // Signs in process, but like a remote signer only sees the bytes to sign.
+        struct MockSigner {
+            inner: InProcessSigner,
+            calls: Cell<usize>,
+            fail: bool,
+        }
+
+        impl Signer for MockSigner {
+            fn public_key(&self) -> &PublicKey {
+                self.inner.public_key()
+            }
+
+            fn sign(&self, bytes: &[u8]) -> Result<[u8; 64], Error> {
+                self.calls.set(self.calls.get() + 1);
+                if self.fail {
+                    return Err(Error::SigningFailed {
+                        reason: "offline".to_owned(),
+                    });
+                }
+                self.inner.sign(bytes)
+            }
+        }
+
+        let (pk, sk) = generate_longterm_keypair();
+        let mut signer = MockSigner {
+            inner: InProcessSigner::new(pk, sk.clone()),
+            calls: Cell::new(0),
+            fail: false,
+        };
+
+        let post = || Content::Plain(Post::new("signed elsewhere"));
+        let options = PublishOptions::default();
+        let msg = publish_with_signer(post(), None::<&[u8]>, &signer, 0.0, &options).unwrap();
+        assert_eq!(signer.calls.get(), 1);
+        verify_message(&msg).unwrap();
+        assert_eq!(msg, publish(post(), None::<&[u8]>, &pk, &sk, 0.0).unwrap());
+
+        let sorted = PublishOptions {
+            content_ordering: ContentOrdering::Sorted,
+            ..PublishOptions::default()
+        };
+        assert_eq!(
+            publish_with_signer(post(), None::<&[u8]>, &signer, 0.0, &sorted).unwrap(),
+            publish_with_options(post(), None::<&[u8]>, &pk, &sk, 0.0, &sorted).unwrap()
+        );
+
+        signer.fail = true;
+        match publish_with_signer(post(), Some(&msg), &signer, 1.0, &options) {
+            Err(Error::SigningFailed { reason }) => assert_eq!(reason, "offline"),
+            other => panic!("expected SigningFailed, got {:?}", other),
+        }
+
+        // Content that can't be published is rejected before anything is signed.
+        let calls = signer.calls.get();
+        match publish_with_signer(Content::Plain("text"), Some(&msg), &signer, 1.0, &options) {
+            Err(Error::ContentNotObject {}) => assert_eq!(signer.calls.get(), calls),
+            other => panic!("expected ContentNotObject, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn one_store_publishes_for_many_feeds() {
+        let (alice, alice_sk) = generate_longterm_keypair();
+        let (bob, bob_sk) = generate_longterm_keypair();
+        let (carol, _) = generate_longterm_keypair();
+        let (alice_id, bob_id) = (crate::author_of(&alice), crate::author_of(&bob));
+
+        let mut store = MemoryKeyStore::new();
+        store.insert(alice, alice_sk);
+        store.insert(bob, bob_sk);
+
+        let post = |text: &str| Content::Plain(Post::new(text));
+        let options = PublishOptions::default();
+        let first = publish_for(
+            &store,
+            &alice_id,
+            post("alice"),
+            None::<&[u8]>,
+            0.0,
+            &options,
+        )
+        .unwrap();
+        let second = publish_for(
+            &store,
+            &alice_id,
+            post("again"),
+            Some(&first),
+            1.0,
+            &options,
+        )
+        .unwrap();
+        let other =
+            publish_for(&store, &bob_id, post("bob"), None::<&[u8]>, 0.0, &options).unwrap();
+
+        assert!(validate_feed(&[first, second]).is_ok());
+        verify_message(&other).unwrap();
+        assert_eq!(ParsedMessage::from_bytes(&other).unwrap().author, bob_id);
+
+        let carol_id = crate::author_of(&carol);
+        match publish_for(
+            &store,
+            &carol_id,
+            post("carol"),
+            None::<&[u8]>,
+            0.0,
+            &options,
+        ) {
+            Err(Error::NoKeysForFeed { feed }) => assert_eq!(feed, carol_id),
+            other => panic!("expected NoKeysForFeed, got {:?}", other),
+        }
+    }
+}
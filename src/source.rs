@@ -0,0 +1,113 @@
+//! Looking up the previous message from wherever feeds are stored.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{
+    author_of, legacy_timestamp, publish_parsed, Content, FeedStore, ParsedMessage, PublishOptions,
+    Result,
+};
+
+/// Somewhere the latest message of a feed can be looked up, such as a database or a log file.
+pub trait PreviousSource {
+    /// The latest message published by `author`, or `None` if they haven't published anything.
+    fn latest(&self, author: &Multikey) -> Result<Option<ParsedMessage>>;
+}
+
+/// A [PreviousSource] that keeps the latest message of each feed in memory.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    latest: HashMap<Multikey, ParsedMessage>,
+}
+
+impl MemorySource {
+    /// An empty source.
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Add a `{key, value}` message, such as one returned by [publish](crate::publish).
+    ///
+    /// It replaces the latest message of its feed unless that one has a higher sequence.
+    pub fn insert(&mut self, msg_bytes: &[u8]) -> Result<()> {
+        let message = ParsedMessage::from_bytes(msg_bytes)?;
+
+        match self.latest.get(&message.author) {
+            Some(latest) if latest.sequence > message.sequence => (),
+            _ => {
+                self.latest.insert(message.author.clone(), message);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PreviousSource for MemorySource {
+    fn latest(&self, author: &Multikey) -> Result<Option<ParsedMessage>> {
+        Ok(self.latest.get(author).cloned())
+    }
+}
+
+impl FeedStore for MemorySource {
+    fn append(&mut self, msg_bytes: &[u8]) -> Result<()> {
+        self.insert(msg_bytes)
+    }
+}
+
+/// Publish a new message, like [publish](crate::publish), after the latest message `source` has
+/// for the feed of `public_key`.
+pub fn publish_from_source<T, S>(
+    content: Content<T>,
+    source: &S,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<Vec<u8>>
+where
+    T: Serialize,
+    S: PreviousSource + ?Sized,
+{
+    let previous = source.latest(&author_of(public_key))?;
+
+    publish_parsed(
+        content,
+        previous.as_ref(),
+        public_key,
+        secret_key,
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+    )
+    .map(|(message, _)| message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{publish_from_source, Content, MemorySource, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_validate::validate_message_hash_chain;
+
+    #[test]
+    fn publish_from_a_memory_source() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut source = MemorySource::new();
+
+        let msg1 =
+            publish_from_source(Content::Plain(Post::new("one")), &source, &pk, &sk, 0.0).unwrap();
+        source.insert(&msg1).unwrap();
+        let msg2 =
+            publish_from_source(Content::Plain(Post::new("two")), &source, &pk, &sk, 1.0).unwrap();
+
+        assert!(validate_message_hash_chain(&msg2, Some(&msg1)).is_ok());
+
+        // An older message doesn't replace the latest one.
+        source.insert(&msg2).unwrap();
+        source.insert(&msg1).unwrap();
+        let post = Content::Plain(Post::new("three"));
+        let msg3 = publish_from_source(post, &source, &pk, &sk, 2.0).unwrap();
+        assert!(validate_message_hash_chain(&msg3, Some(&msg2)).is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+//! The stages [publish](crate::publish) goes through, as separate functions.
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_legacy_msg::Message;
+use ssb_legacy_msg_data::value::{RidiculousStringMap, Value};
+use ssb_legacy_msg_data::LegacyF64;
+use ssb_multiformats::multihash::Multihash;
+use ssb_multiformats::multikey::{Multikey, Multisig};
+
+use crate::canonical::{self, CanonicalJson};
+use crate::hasher::{self, MessageHasher};
+use crate::signer;
+use crate::{author_of, Content, ParsedMessage, Result};
+
+/// The unsigned message publishing `content` after `previous` in the feed of `public_key`.
+///
+/// Nothing is checked here, not even that `previous` belongs to the same feed.
+///
+/// This is the first stage of publishing. Chaining the stages gives the same message as
+/// [publish](crate::publish), without its checks:
+///
+/// ```
+/// use ssb_crypto::generate_longterm_keypair;
+/// use ssb_legacy_msg_data::LegacyF64;
+/// use ssb_publish::{
+///     assemble_wrapper, build_message, compute_key, encode_signable, sign, Content, Post,
+/// };
+/// use ssb_verify_signatures::verify_message;
+///
+/// let (pk, sk) = generate_longterm_keypair();
+///
+/// let timestamp = LegacyF64::from_f64(0.0).unwrap();
+/// let mut message = build_message(Content::Plain(Post::new("hi")), None, &pk, timestamp);
+/// let signable = encode_signable(&message).unwrap();
+/// // The signature may just as well be made elsewhere, eg. on a hardware key.
+/// message.signature = Some(sign(&signable, &sk));
+///
+/// let value = encode_signable(&message).unwrap();
+/// let key = compute_key(&value);
+/// let msg = assemble_wrapper(&key, &value).unwrap();
+///
+/// assert!(verify_message(&msg).is_ok());
+/// ```
+pub fn build_message<T>(
+    content: Content<T>,
+    previous: Option<&ParsedMessage>,
+    public_key: &PublicKey,
+    timestamp: LegacyF64,
+) -> Message<T> {
+    Message {
+        content,
+        author: author_of(public_key),
+        previous: previous.map(|msg| msg.key.clone()),
+        sequence: previous.map_or(1, |msg| msg.sequence + 1),
+        swapped: false,
+        timestamp,
+        signature: None,
+    }
+}
+
+/// Encode `message` in the signing format.
+///
+/// Without a signature these are the bytes to [sign]. Once the signature is set, they are the
+/// message value, from which [compute_key] computes the key.
+pub fn encode_signable<T: Serialize>(message: &Message<T>) -> Result<Vec<u8>> {
+    canonical::DEFAULT.encode_message(message)
+}
+
+/// Sign the bytes from [encode_signable] with `secret_key`.
+pub fn sign(bytes: &[u8], secret_key: &SecretKey) -> Multisig {
+    Multisig::from_ed25519(&signer::sign_ed25519(bytes, secret_key))
+}
+
+/// The key of the signed message value `value_bytes`.
+pub fn compute_key(value_bytes: &[u8]) -> Multihash {
+    hasher::DEFAULT.hash(value_bytes)
+}
+
+/// The `{key, value}` message of a signed message value and its key.
+pub fn assemble_wrapper(key: &Multihash, value_bytes: &[u8]) -> Result<Vec<u8>> {
+    let value = canonical::DEFAULT.decode_value(value_bytes)?;
+    wrap(key, value)
+}
+
+pub(crate) fn wrap(key: &Multihash, value: Value) -> Result<Vec<u8>> {
+    canonical::DEFAULT.encode_value(&Value::Object(wrapper(key, value)))
+}
+
+// `wrap`, also listing the ids of `recps` in a `recps` entry next to the key. The entry is outside
+// the signed value, so it changes neither the signature nor the key.
+pub(crate) fn wrap_with_recps(
+    key: &Multihash,
+    value: Value,
+    recps: &[Multikey],
+) -> Result<Vec<u8>> {
+    let mut map = wrapper(key, value);
+    let recps = recps
+        .iter()
+        .map(|recipient| Value::String(recipient.to_legacy_string()))
+        .collect();
+    map.insert("recps".to_owned(), Value::Array(recps));
+
+    canonical::DEFAULT.encode_value(&Value::Object(map))
+}
+
+fn wrapper(key: &Multihash, value: Value) -> RidiculousStringMap<Value> {
+    let mut map = RidiculousStringMap::with_capacity(2);
+    map.insert("key".to_owned(), Value::String(key.to_legacy_string()));
+    map.insert("value".to_owned(), value);
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assemble_wrapper, build_message, compute_key, encode_signable, publish, sign, Content,
+        ParsedMessage, Post,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::LegacyF64;
+
+    #[test]
+    fn chained_stages_match_publish() {
+        let (pk, sk) = generate_longterm_keypair();
+        let first =
+            publish::<_, &[u8]>(Content::Plain(Post::new("one")), None, &pk, &sk, 1.0).unwrap();
+        let expected = publish(
+            Content::Plain(Post::new("two")),
+            Some(&first),
+            &pk,
+            &sk,
+            2.0,
+        )
+        .unwrap();
+
+        let previous = ParsedMessage::from_bytes(&first).unwrap();
+        let timestamp = LegacyF64::from_f64(2.0).unwrap();
+        let mut message = build_message(
+            Content::Plain(Post::new("two")),
+            Some(&previous),
+            &pk,
+            timestamp,
+        );
+        let signable = encode_signable(&message).unwrap();
+        message.signature = Some(sign(&signable, &sk));
+        let value = encode_signable(&message).unwrap();
+        let msg = assemble_wrapper(&compute_key(&value), &value).unwrap();
+
+        assert_eq!(msg, expected);
+    }
+}
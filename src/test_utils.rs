@@ -0,0 +1,141 @@
+//! Helpers for testing content types and publishing. Enable the `test-utils` feature to use them.
+
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use ssb_crypto::{generate_longterm_keypair, PublicKey, SecretKey};
+use ssb_multiformats::multikey::Multikey;
+
+use crate::{content_as, publish, Contact, Content};
+
+/// The feed id of the examples in the ssb documentation, for content that refers to some feed.
+pub const EXAMPLE_FEED_ID: &str = "@9Zf0se86PotjNqaOt9ue8BNBLkGVLQcLNDw/pRQHY3U=.ed25519";
+
+/// A [Contact] following or unfollowing [EXAMPLE_FEED_ID], for tests that need some plain content
+/// to publish.
+pub fn example_contact(following: bool) -> Contact {
+    Contact {
+        contact: Multikey::from_legacy(EXAMPLE_FEED_ID.as_bytes()).unwrap().0,
+        following,
+        blocking: false,
+    }
+}
+
+/// Publish `content` with a fresh key, read it back with [content_as], and assert it is unchanged.
+///
+/// This catches mistakes in serde attributes, such as a missing `type` tag, in one line.
+///
+/// # Panics
+///
+/// If publishing or decoding fails, or the decoded content differs from `content`.
+pub fn assert_content_roundtrip<T>(content: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug + Clone,
+{
+    let (pk, sk) = generate_longterm_keypair();
+
+    let msg = publish::<_, &[u8]>(Content::Plain(content.clone()), None, &pk, &sk, 0.0)
+        .expect("publishing the content failed");
+    let decoded = content_as::<T>(&msg).expect("decoding the published content failed");
+
+    assert_eq!(decoded, content);
+}
+
+/// Publish plain `content` as the message after `previous`, and assert the result matches the
+/// snapshot at `path`.
+///
+/// The snapshot records the inputs along with the published message, as json. If there is no
+/// snapshot at `path` yet, or the `UPDATE_SNAPSHOTS` environment variable is set, it is written
+/// instead, to be committed. Later runs fail if the inputs or the message bytes differ, catching
+/// any change to the output across refactors and dependency upgrades.
+///
+/// `secret_key` is written into the snapshot, so only use keys made for tests.
+///
+/// # Panics
+///
+/// If publishing fails, the snapshot can't be read or written, or it doesn't match.
+pub fn assert_publish_snapshot<T, P>(
+    path: P,
+    content: &T,
+    previous: Option<&[u8]>,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) where
+    T: Serialize,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let public_key = PublicKey::from_slice(&secret_key[32..]).expect("invalid secret key");
+
+    let message = publish(
+        Content::Plain(content),
+        previous,
+        &public_key,
+        secret_key,
+        timestamp,
+    )
+    .expect("publishing the content failed");
+
+    let snapshot = Snapshot {
+        content: serde_json::to_value(content).expect("content is not valid json"),
+        previous: previous.map(|previous| String::from_utf8_lossy(previous).into_owned()),
+        secret_key: base64::encode(&secret_key[..]),
+        timestamp,
+        message: String::from_utf8(message).expect("published message is not utf-8"),
+    };
+
+    if !path.exists() || std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("creating the snapshot directory failed");
+        }
+        let json = serde_json::to_string_pretty(&snapshot).unwrap() + "\n";
+        fs::write(path, json).expect("writing the snapshot failed");
+        return;
+    }
+
+    let recorded = fs::read_to_string(path).expect("reading the snapshot failed");
+    let recorded: Snapshot = serde_json::from_str(&recorded).expect("invalid snapshot");
+    assert_eq!(
+        snapshot,
+        recorded,
+        "published message differs from the snapshot at {}",
+        path.display()
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Snapshot {
+    content: serde_json::Value,
+    previous: Option<String>,
+    secret_key: String,
+    timestamp: f64,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{assert_publish_snapshot, example_contact};
+
+    #[test]
+    fn contact_matches_its_snapshot() {
+        // A key made for this test, it is recorded in the snapshot.
+        let sk = base64::decode(
+            "7Y9Fd3V5NJvf0g92e0Djvp6rc1InFGcf+IxMpzRDFWBDj+ifCsCAvSmJsSkIpjI7ckwiJKXeLuVCFv/uUI8P7g==",
+        )
+        .unwrap();
+        let sk = ssb_crypto::SecretKey::from_slice(&sk).unwrap();
+
+        let contact = example_contact(true);
+
+        assert_publish_snapshot(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots/contact.json"),
+            &contact,
+            None,
+            &sk,
+            1_470_186_877_575.0,
+        );
+    }
+}
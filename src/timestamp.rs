@@ -0,0 +1,137 @@
+//! Choosing the timestamps of new messages.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use snafu::{ensure, OptionExt};
+use ssb_legacy_msg_data::LegacyF64;
+
+use crate::{Result, TimestampOutOfRange};
+
+/// The largest timestamp [ms_to_legacy] accepts, javascript's `Number.MAX_SAFE_INTEGER`. Every
+/// whole number of milliseconds up to it is exact as a float.
+pub const MAX_TIMESTAMP_MS: i64 = (1 << 53) - 1;
+
+/// A timestamp in milliseconds since the unix epoch, as a `LegacyF64`.
+///
+/// Fails with [Error::TimestampOutOfRange](crate::Error::TimestampOutOfRange) if `ms` is negative
+/// or larger than [MAX_TIMESTAMP_MS].
+pub fn ms_to_legacy(ms: i64) -> Result<LegacyF64> {
+    ensure!(
+        (0..=MAX_TIMESTAMP_MS).contains(&ms),
+        TimestampOutOfRange { ms }
+    );
+    LegacyF64::from_f64(ms as f64).context(TimestampOutOfRange { ms })
+}
+
+/// A `LegacyF64` timestamp in whole milliseconds since the unix epoch.
+///
+/// Fractions of a millisecond are dropped, and timestamps outside the range of `i64` are clamped
+/// to it.
+pub fn legacy_to_ms(timestamp: LegacyF64) -> i64 {
+    f64::from(timestamp) as i64
+}
+
+/// How the timestamp of a new message is chosen, see
+/// [Feed::publish_with_policy](crate::Feed::publish_with_policy).
+pub enum TimestampPolicy {
+    /// The system clock, in milliseconds since the unix epoch.
+    Clock,
+    /// A random whole number of milliseconds between the unix epoch and now, drawn from the given
+    /// random number generator. This hides when messages were really published.
+    ///
+    /// Use [TimestampPolicy::random] for a generator seeded by the operating system, or pass a
+    /// deterministic one in tests.
+    Random(Box<dyn FnMut() -> u64>),
+}
+
+impl TimestampPolicy {
+    /// Random timestamps, from a generator seeded by the operating system.
+    pub fn random() -> TimestampPolicy {
+        TimestampPolicy::Random(Box::new(rand::random))
+    }
+
+    /// The timestamp for the next message.
+    pub fn next_timestamp(&mut self) -> f64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        match self {
+            TimestampPolicy::Clock => now as f64,
+            // Anything up to now is a valid `LegacyF64`, and passes the future skew check.
+            TimestampPolicy::Random(rng) => (rng() % (now + 1)) as f64,
+        }
+    }
+}
+
+impl fmt::Debug for TimestampPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimestampPolicy::Clock => write!(f, "Clock"),
+            TimestampPolicy::Random(_) => write!(f, "Random(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        legacy_to_ms, ms_to_legacy, Content, Error, Feed, ParsedMessage, Post, TimestampPolicy,
+        MAX_TIMESTAMP_MS,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_legacy_msg_data::LegacyF64;
+    use ssb_validate::validate_message_hash_chain;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn random_timestamps_still_validate() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut feed = Feed::new(pk, sk);
+
+        let mut seed = 42u64;
+        let mut policy = TimestampPolicy::Random(Box::new(move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            seed
+        }));
+
+        let msg1 = feed
+            .publish_with_policy(Content::Plain(Post::new("one")), &mut policy)
+            .unwrap();
+        let msg2 = feed
+            .publish_with_policy(Content::Plain(Post::new("two")), &mut policy)
+            .unwrap();
+
+        assert!(validate_message_hash_chain::<_, &[u8]>(&msg1, None).is_ok());
+        assert!(validate_message_hash_chain(&msg2, Some(&msg1)).is_ok());
+        assert!(verify_message(&msg2).is_ok());
+
+        let timestamp = f64::from(ParsedMessage::from_bytes(&msg1).unwrap().timestamp);
+        assert_eq!(timestamp.fract(), 0.0);
+
+        let mut policy = TimestampPolicy::random();
+        let post = Content::Plain(Post::new("three"));
+        assert!(feed.publish_with_policy(post, &mut policy).is_ok());
+    }
+
+    #[test]
+    fn convert_between_ms_and_legacy_timestamps() {
+        for ms in &[0, 1, 1_470_186_877_575, MAX_TIMESTAMP_MS] {
+            assert_eq!(legacy_to_ms(ms_to_legacy(*ms).unwrap()), *ms);
+        }
+
+        for ms in &[-1, MAX_TIMESTAMP_MS + 1, i64::MAX, i64::MIN] {
+            match ms_to_legacy(*ms) {
+                Err(Error::TimestampOutOfRange { ms: out_of_range }) => {
+                    assert_eq!(out_of_range, *ms)
+                }
+                other => panic!("expected {} to be out of range, got {:?}", ms, other),
+            }
+        }
+
+        let fractional = LegacyF64::from_f64(1470186877575.5).unwrap();
+        assert_eq!(legacy_to_ms(fractional), 1_470_186_877_575);
+    }
+}
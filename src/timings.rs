@@ -0,0 +1,114 @@
+//! Measuring how long the stages of publishing take.
+
+#[cfg(feature = "timings")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "timings")]
+use serde::Serialize;
+#[cfg(feature = "timings")]
+use ssb_crypto::{PublicKey, SecretKey};
+
+#[cfg(feature = "timings")]
+use crate::{
+    legacy_timestamp, parse_previous, publish_parsed_timed, Content, InProcessSigner,
+    PublishOptions, Result,
+};
+
+/// A stage of publishing that is timed.
+pub(crate) enum Stage {
+    Encode,
+    Sign,
+    Hash,
+}
+
+/// Where publishing reports how long each stage took. `()` ignores them without measuring.
+pub(crate) trait StageTimer {
+    fn time<R, F: FnOnce() -> R>(&mut self, stage: Stage, f: F) -> R;
+}
+
+impl StageTimer for () {
+    fn time<R, F: FnOnce() -> R>(&mut self, _: Stage, f: F) -> R {
+        f()
+    }
+}
+
+/// How long the stages of publishing one message took, see [publish_timed].
+#[cfg(feature = "timings")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PublishTimings {
+    /// Encoding the message in the signing format, before and after signing it, and encoding the
+    /// final `{key, value}` message.
+    pub encode: Duration,
+    /// Signing the message.
+    pub sign: Duration,
+    /// Hashing the message into its key.
+    pub hash: Duration,
+}
+
+#[cfg(feature = "timings")]
+impl StageTimer for PublishTimings {
+    fn time<R, F: FnOnce() -> R>(&mut self, stage: Stage, f: F) -> R {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+
+        match stage {
+            Stage::Encode => self.encode += elapsed,
+            Stage::Sign => self.sign += elapsed,
+            Stage::Hash => self.hash += elapsed,
+        }
+        result
+    }
+}
+
+/// Publish a new message, like [publish](crate::publish), also returning how long its stages
+/// took, eg. for reporting to a metrics system.
+#[cfg(feature = "timings")]
+pub fn publish_timed<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<(Vec<u8>, PublishTimings)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let previous = parse_previous(previous_msg_value_bytes)?;
+
+    let mut timings = PublishTimings::default();
+    let (message, _) = publish_parsed_timed(
+        content,
+        previous.as_ref(),
+        &InProcessSigner::new(*public_key, secret_key.clone()),
+        legacy_timestamp(timestamp)?,
+        &PublishOptions::default(),
+        &mut timings,
+    )?;
+    Ok((message, timings))
+}
+
+#[cfg(all(test, feature = "timings"))]
+mod tests {
+    use crate::{Content, Post};
+    use ssb_crypto::generate_longterm_keypair;
+    use ssb_verify_signatures::verify_message;
+
+    #[test]
+    fn timings_are_recorded() {
+        use crate::publish_timed;
+        use std::time::Instant;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let start = Instant::now();
+        let (msg, timings) =
+            publish_timed::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0)
+                .unwrap();
+        let elapsed = start.elapsed();
+        assert!(verify_message(&msg).is_ok());
+
+        // The stages run one after the other, within the whole call.
+        assert!(timings.encode + timings.sign + timings.hash <= elapsed);
+    }
+}
@@ -0,0 +1,73 @@
+//! `ssb:` URIs for messages.
+
+use serde::Serialize;
+use ssb_crypto::{PublicKey, SecretKey};
+use ssb_multiformats::multihash::Multihash;
+
+use crate::{publish_indexed, Content, Result};
+
+/// The `ssb:message/sha256/...` URI of a message key.
+///
+/// The hash is encoded as url safe base64, `+` and `/` replaced by `-` and `_`, with its padding.
+pub fn message_uri(key: &Multihash) -> String {
+    let legacy = key.to_legacy_string();
+    let hash = legacy
+        .trim_start_matches('%')
+        .trim_end_matches(".sha256")
+        .replace('+', "-")
+        .replace('/', "_");
+
+    format!("ssb:message/sha256/{}", hash)
+}
+
+/// Publish a new message, like [publish](crate::publish), returning both its legacy `{key,
+/// value}` bytes and its [message_uri], along with its key.
+pub fn publish_dual<T, P>(
+    content: Content<T>,
+    previous_msg_value_bytes: Option<P>,
+    public_key: &PublicKey,
+    secret_key: &SecretKey,
+    timestamp: f64,
+) -> Result<(Vec<u8>, String, Multihash)>
+where
+    T: Serialize,
+    P: AsRef<[u8]>,
+{
+    let (message, entry) = publish_indexed(
+        content,
+        previous_msg_value_bytes,
+        public_key,
+        secret_key,
+        timestamp,
+    )?;
+    let uri = message_uri(&entry.key);
+
+    Ok((message, uri, entry.key))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{message_uri, publish_dual, Content, ParsedMessage, Post};
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn publish_dual_forms_share_a_key() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let (msg, uri, key) =
+            publish_dual::<_, &[u8]>(Content::Plain(Post::new("hi")), None, &pk, &sk, 0.0).unwrap();
+
+        assert_eq!(ParsedMessage::from_bytes(&msg).unwrap().key, key);
+        assert_eq!(uri, message_uri(&key));
+
+        let legacy = key.to_legacy_string();
+        let hash = &legacy[1..legacy.len() - ".sha256".len()];
+        assert!(uri.starts_with("ssb:message/sha256/"));
+        assert_eq!(
+            uri["ssb:message/sha256/".len()..]
+                .replace('-', "+")
+                .replace('_', "/"),
+            hash
+        );
+    }
+}
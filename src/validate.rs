@@ -0,0 +1,314 @@
+//! Checking existing feeds before publishing onto them.
+
+use std::collections::HashMap;
+
+use snafu::{ensure, ResultExt};
+use ssb_legacy_msg_data::json::from_slice;
+use ssb_multiformats::multikey::Multikey;
+use ssb_validate::validate_message_hash_chain;
+use ssb_verify_signatures::verify_message;
+
+use crate::canonical::{self, CanonicalJson};
+use crate::hasher::{self, MessageHasher};
+use crate::{
+    InvalidFeedMessage, InvalidFeedSignature, InvalidInterleavedMessage, InvalidMessage,
+    KeyMismatch, ParsedMessage, Result, SsbMessage, SsbPreviousMessage,
+};
+
+/// Validate the hash chain and verify the signatures of every message of a feed.
+///
+/// `messages` must yield the `{key, value}` messages of a single feed in order, starting from its
+/// first message. They are checked one at a time, so the feed is never buffered.
+///
+/// Returns the last message of the feed, or `None` if the feed is empty.
+pub fn validate_feed<I>(messages: I) -> Result<Option<I::Item>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut previous: Option<I::Item> = None;
+
+    for message in messages {
+        check_link(message.as_ref(), previous.as_ref().map(AsRef::as_ref))?;
+        check_signature(message.as_ref())?;
+
+        previous = Some(message);
+    }
+
+    Ok(previous)
+}
+
+/// What [validate_feed_with_report] found in a valid feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedReport<T> {
+    /// The last message of the feed, or `None` if the feed is empty.
+    pub latest: Option<T>,
+    /// The sequence numbers of the messages with an earlier timestamp than the message before
+    /// them, in order.
+    ///
+    /// Such timestamps are allowed, but suggest that the publisher's clock was wrong, so importers
+    /// may want to point them out.
+    pub timestamp_regressions: Vec<u64>,
+}
+
+/// Validate a feed like [validate_feed], also reporting what is valid but suspicious about it.
+pub fn validate_feed_with_report<I>(messages: I) -> Result<FeedReport<I::Item>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut previous: Option<(I::Item, ParsedMessage)> = None;
+    let mut timestamp_regressions = Vec::new();
+
+    for message in messages {
+        let previous_bytes = previous.as_ref().map(|(bytes, _)| bytes.as_ref());
+        check_link(message.as_ref(), previous_bytes)?;
+        check_signature(message.as_ref())?;
+
+        let parsed = ParsedMessage::from_bytes(message.as_ref())?;
+        if let Some((_, previous)) = &previous {
+            if f64::from(parsed.timestamp) < f64::from(previous.timestamp) {
+                timestamp_regressions.push(parsed.sequence);
+            }
+        }
+
+        previous = Some((message, parsed));
+    }
+
+    Ok(FeedReport {
+        latest: previous.map(|(message, _)| message),
+        timestamp_regressions,
+    })
+}
+
+/// Validate the messages of several feeds like [validate_feed], when they are interleaved, eg. as
+/// they arrive from a bulk import.
+///
+/// The messages of each feed must come in order, starting from its first message, but may be
+/// mixed in any way with the messages of other feeds. Each feed is validated on its own. Fails
+/// with [Error::InvalidInterleavedMessage](crate::Error::InvalidInterleavedMessage) naming the
+/// author and sequence number of the first invalid message.
+pub fn validate_interleaved<I>(messages: I) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut latest: HashMap<Multikey, I::Item> = HashMap::new();
+
+    for message in messages {
+        let parsed = ParsedMessage::from_bytes(message.as_ref())?;
+        let previous = latest.get(&parsed.author).map(AsRef::as_ref);
+
+        check_link(message.as_ref(), previous)
+            .and_then(|_| check_signature(message.as_ref()))
+            .map_err(Box::new)
+            .context(InvalidInterleavedMessage {
+                author: parsed.author.clone(),
+                sequence: parsed.sequence,
+            })?;
+
+        latest.insert(parsed.author, message);
+    }
+
+    Ok(())
+}
+
+/// Check that the `key` of every message is the hash of its `value`.
+///
+/// This recomputes each key the same way [publish](crate::publish) does. Run it over a known good
+/// feed after upgrading the hashing or encoding dependencies, to make sure keys are unchanged.
+/// Fails with the first message whose key does not match.
+pub fn verify_keys_match<I>(messages: I) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    for message in messages {
+        let message = from_slice::<SsbMessage>(message.as_ref()).context(InvalidMessage)?;
+        let value_bytes = canonical::DEFAULT.encode_value(&message.value)?;
+        let actual = hasher::DEFAULT.hash(&value_bytes);
+
+        ensure!(
+            actual == message.key,
+            KeyMismatch {
+                key: message.key,
+                actual
+            }
+        );
+    }
+
+    Ok(())
+}
+
+// Check that `message` follows `previous`, failing with the sequence number and key of `message`
+// if it has them.
+pub(crate) fn check_link(message: &[u8], previous: Option<&[u8]>) -> Result<()> {
+    validate_message_hash_chain(message, previous).with_context(|| {
+        let (sequence, key) = message_id(message);
+        InvalidFeedMessage { sequence, key }
+    })
+}
+
+// Verify the signature of `message`, failing with its sequence number and key if it has them.
+pub(crate) fn check_signature(message: &[u8]) -> Result<()> {
+    verify_message(message).with_context(|| {
+        let (sequence, key) = message_id(message);
+        InvalidFeedSignature { sequence, key }
+    })
+}
+
+// The sequence number and key of a message, for reporting what is wrong with it. Invalid messages
+// may not have them.
+fn message_id(message: &[u8]) -> (Option<u64>, Option<String>) {
+    match from_slice::<SsbPreviousMessage>(message) {
+        Ok(message) => (
+            Some(message.value.sequence),
+            Some(message.key.to_legacy_string()),
+        ),
+        Err(_) => (None, None),
+    }
+}
+
+// A message in an error, by whichever of its sequence number and key are known.
+pub(crate) fn describe_message(sequence: &Option<u64>, key: &Option<String>) -> String {
+    match (sequence, key) {
+        (Some(sequence), Some(key)) => format!("{} ({})", sequence, key),
+        (Some(sequence), None) => sequence.to_string(),
+        (None, Some(key)) => key.clone(),
+        (None, None) => "(unreadable)".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::example_contact;
+    use crate::{
+        publish, validate_feed, validate_feed_with_report, validate_interleaved, verify_keys_match,
+        Content, Error, ParsedMessage, Post,
+    };
+    use ssb_crypto::generate_longterm_keypair;
+
+    #[test]
+    fn keys_match_their_values() {
+        let (pk, sk) = generate_longterm_keypair();
+
+        let msg1 = publish::<_, &[u8]>(Content::Plain(example_contact(true)), None, &pk, &sk, 0.0)
+            .unwrap();
+        let msg2 = publish(
+            Content::Plain(example_contact(false)),
+            Some(&msg1),
+            &pk,
+            &sk,
+            1.0,
+        )
+        .unwrap();
+        assert!(verify_keys_match(vec![&msg1, &msg2]).is_ok());
+
+        let tampered = String::from_utf8(msg2)
+            .unwrap()
+            .replace(r#""following": false"#, r#""following": true"#);
+
+        match verify_keys_match(vec![msg1.as_slice(), tampered.as_bytes()]) {
+            Err(Error::KeyMismatch { .. }) => (),
+            other => panic!("expected a key mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timestamp_regressions_are_reported() {
+        let (pk, sk) = generate_longterm_keypair();
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for &timestamp in &[1.0, 3.0, 2.0, 4.0, 4.0] {
+            let msg = publish(
+                Content::Plain(Post::new("hi")),
+                feed.last(),
+                &pk,
+                &sk,
+                timestamp,
+            )
+            .unwrap();
+            feed.push(msg);
+        }
+
+        let report = validate_feed_with_report(&feed).unwrap();
+        assert_eq!(report.latest, feed.last());
+        assert_eq!(report.timestamp_regressions, vec![3]);
+    }
+
+    #[test]
+    fn interleaved_feeds_are_validated_separately() {
+        let feed_of = |length: usize| {
+            let (pk, sk) = generate_longterm_keypair();
+            let mut feed: Vec<Vec<u8>> = Vec::new();
+            for i in 0..length {
+                let post = Content::Plain(Post::new(format!("{}", i)));
+                let msg = publish(post, feed.last(), &pk, &sk, i as f64).unwrap();
+                feed.push(msg);
+            }
+            (crate::author_of(&pk), feed)
+        };
+        let (_, alice) = feed_of(3);
+        let (_, bob) = feed_of(2);
+        let (carol_id, carol) = feed_of(3);
+
+        let interleaved = [&alice[0], &bob[0], &alice[1], &bob[1], &alice[2]];
+        validate_interleaved(interleaved.iter().copied()).unwrap();
+
+        let broken = [&alice[0], &carol[0], &bob[0], &alice[1], &carol[2], &bob[1]];
+        match validate_interleaved(broken.iter().copied()) {
+            Err(Error::InvalidInterleavedMessage {
+                author, sequence, ..
+            }) => {
+                assert_eq!(author, carol_id);
+                assert_eq!(sequence, 3);
+            }
+            other => panic!("expected InvalidInterleavedMessage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_feed_messages_are_reported_with_their_position() {
+        use std::error::Error as _;
+
+        let (pk, sk) = generate_longterm_keypair();
+        let mut feed: Vec<Vec<u8>> = Vec::new();
+        for i in 0..3 {
+            let post = Content::Plain(Post::new(format!("{}", i)));
+            let msg = publish(post, feed.last(), &pk, &sk, i as f64).unwrap();
+            feed.push(msg);
+        }
+        let key = ParsedMessage::from_bytes(&feed[1])
+            .unwrap()
+            .key
+            .to_legacy_string();
+        feed[1] = String::from_utf8(feed[1].clone())
+            .unwrap()
+            .replace(r#""text": "1""#, r#""text": "one""#)
+            .into_bytes();
+
+        let err = validate_feed(&feed).unwrap_err();
+        match &err {
+            Error::InvalidFeedMessage {
+                sequence, key: k, ..
+            } => {
+                assert_eq!(*sequence, Some(2));
+                assert_eq!(k.as_ref(), Some(&key));
+            }
+            other => panic!("expected InvalidFeedMessage, got {:?}", other),
+        }
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Feed message 2 ({}) ", key)));
+        assert!(err.source().is_some());
+
+        // A message too broken to name is still reported with what is wrong with it.
+        let err = validate_feed(&[b"not a message"]).unwrap_err();
+        match &err {
+            Error::InvalidFeedMessage { sequence, key, .. } => {
+                assert_eq!((sequence, key), (&None, &None));
+            }
+            other => panic!("expected InvalidFeedMessage, got {:?}", other),
+        }
+        assert!(err.source().is_some());
+    }
+}